@@ -0,0 +1,130 @@
+// Benchmarks for the three stages of the engine:
+// deserializing the csv input, applying transactions to the accounts and the whole pipeline.
+// The input is produced by the deterministic generator, so the numbers are comparable between runs.
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use csv_async::{AsyncReaderBuilder, Trim};
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::runtime::{Builder as RuntimeBuilder, Runtime};
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+
+use tx_engine::accounts::Manager;
+use tx_engine::assets::Assets;
+use tx_engine::cli::{ProcessOptions, DEFAULT_SNAPSHOT_INTERVAL};
+use tx_engine::generate::{self, Config, Generator};
+use tx_engine::metrics::Metrics;
+use tx_engine::pipeline;
+use tx_engine::storage::InMemoryKVStore;
+use tx_engine::types::{Account, AccountKey, Transaction, TransactionID, TransactionRow};
+use tx_engine::verify::VerifyMode;
+
+const ROWS: u64 = 100_000;
+
+fn runtime() -> Runtime {
+    RuntimeBuilder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+fn input(runtime: &Runtime) -> Vec<u8> {
+    let mut generator = Generator::new(Config {
+        seed: 1,
+        clients: 1000,
+        rows: ROWS,
+        ..Default::default()
+    });
+    let mut csv = Vec::new();
+    runtime
+        .block_on(generate::write_transactions(&mut generator, &mut csv))
+        .unwrap();
+    csv
+}
+
+async fn parse(csv: Vec<u8>) -> Vec<Transaction> {
+    let mut reader = AsyncReaderBuilder::new()
+        .trim(Trim::All)
+        .create_deserializer(Cursor::new(csv));
+    let mut records = reader.deserialize::<TransactionRow>();
+    let assets = Assets::default();
+    let mut txs = Vec::with_capacity(ROWS as usize);
+    while let Some(row) = records.next().await {
+        txs.push(assets.transaction(row.unwrap()).unwrap());
+    }
+    txs
+}
+
+async fn apply(txs: Vec<Transaction>) {
+    let account_store = Arc::new(Mutex::new(InMemoryKVStore::<AccountKey, Account>::new().unwrap()));
+    let tx_store = Arc::new(Mutex::new(
+        InMemoryKVStore::<TransactionID, Transaction>::new().unwrap(),
+    ));
+    let mut mgr = Manager::new(account_store, tx_store);
+    for tx in txs {
+        let _ = mgr.process_transaction(tx).await;
+    }
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let runtime = runtime();
+    let csv = input(&runtime);
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Elements(ROWS));
+    group.sample_size(20);
+    group.bench_function("transaction_rows", |b| {
+        b.iter_batched(|| csv.clone(), |csv| runtime.block_on(parse(csv)), BatchSize::LargeInput)
+    });
+    group.finish();
+}
+
+fn bench_apply(c: &mut Criterion) {
+    let runtime = runtime();
+    let txs = runtime.block_on(parse(input(&runtime)));
+    let mut group = c.benchmark_group("apply");
+    group.throughput(Throughput::Elements(ROWS));
+    group.sample_size(20);
+    group.bench_function("process_transaction", |b| {
+        b.iter_batched(|| txs.clone(), |txs| runtime.block_on(apply(txs)), BatchSize::LargeInput)
+    });
+    group.finish();
+}
+
+fn bench_pipeline(c: &mut Criterion) {
+    let runtime = runtime();
+    let csv = input(&runtime);
+    let opts = ProcessOptions {
+        input: PathBuf::new(),
+        history: None,
+        snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+        verify: VerifyMode::Off,
+        policy: None,
+        assets: None,
+        fees: None,
+        limits: None,
+        metrics: None,
+        metrics_addr: None,
+        summary: None,
+        results: None,
+        pending_credit: false,
+        deficit: false,
+    };
+    let mut group = c.benchmark_group("pipeline");
+    group.throughput(Throughput::Elements(ROWS));
+    group.sample_size(20);
+    group.bench_function("end_to_end", |b| {
+        b.iter_batched(
+            || csv.clone(),
+            |csv| runtime
+                    .block_on(pipeline::run(Cursor::new(csv), &opts, Arc::new(Metrics::new())))
+                    .unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse, bench_apply, bench_pipeline);
+criterion_main!(benches);
@@ -0,0 +1,46 @@
+// Feeds arbitrary bytes through the csv reader of the engine and applies every row that can be parsed.
+// The engine must neither panic nor break any of the account invariants.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+
+use tx_engine::accounts::Manager;
+use tx_engine::assets::Assets;
+use tx_engine::error::Error;
+use tx_engine::pipeline;
+use tx_engine::storage::InMemoryKVStore;
+use tx_engine::types::{Account, AccountKey, Transaction, TransactionID, TransactionRow};
+use tx_engine::verify::{self, VerifyMode};
+
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    runtime.block_on(async {
+        let account_store = Arc::new(Mutex::new(InMemoryKVStore::<AccountKey, Account>::new().unwrap()));
+        let tx_store = Arc::new(Mutex::new(
+            InMemoryKVStore::<TransactionID, Transaction>::new().unwrap(),
+        ));
+        let mut mgr =
+            Manager::new(account_store.clone(), tx_store).with_verify_mode(VerifyMode::EachTransaction);
+
+        // a few assets with other precisions than the default, so parsing with all of them is exercised
+        let assets: Assets = "JPY = 0\nBTC = 8\nETH = 18\n".parse().unwrap();
+
+        let mut reader = pipeline::reader(Cursor::new(data.to_vec()));
+        let mut records = reader.deserialize::<TransactionRow>();
+        while let Some(row) = records.next().await {
+            let tx = match row.map(|row| assets.transaction(row)) {
+                Ok(Ok(tx)) => tx,
+                _ => continue,
+            };
+            if let Err(Error::InvariantViolation(reason)) = mgr.process_transaction(tx).await {
+                panic!("{}", reason);
+            }
+        }
+
+        let accounts = account_store.lock().await.clone();
+        verify::check_accounts(accounts.into_iter().map(|(_, a)| a), mgr.ledgers()).unwrap();
+    });
+});
@@ -0,0 +1,92 @@
+// Applies arbitrary sequences of transactions to the account manager.
+// Clients and transaction ids are drawn from small ranges, so disputes frequently hit existing transactions.
+// The engine must neither panic nor break any of the account invariants.
+#![no_main]
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use tx_engine::accounts::Manager;
+use tx_engine::error::Error;
+use tx_engine::policy::{DisputeAmounts, DisputeTracking, Policy, WithdrawalDisputes};
+use tx_engine::storage::InMemoryKVStore;
+use tx_engine::types::{Account, AccountKey, ClientID, Currency, Transaction, TransactionID, TxType, SECONDS_PER_DAY};
+use tx_engine::verify::{self, VerifyMode};
+
+#[derive(Debug, Arbitrary)]
+struct Op {
+    type_: u8,
+    client: u8,
+    tx: u8,
+    amount: Option<u64>,
+    destination: u8,
+    // the day the transaction happens on, days can go back as well
+    day: Option<u8>,
+}
+
+impl From<&Op> for Transaction {
+    fn from(op: &Op) -> Self {
+        let type_ = match op.type_ % 11 {
+            0 => TxType::Deposit,
+            1 => TxType::Withdrawal,
+            2 => TxType::Dispute,
+            3 => TxType::Resolve,
+            4 => TxType::Chargeback,
+            5 => TxType::Transfer,
+            6 => TxType::Authorize,
+            7 => TxType::Capture,
+            8 => TxType::Void,
+            9 => TxType::Hold,
+            _ => TxType::Release,
+        };
+        Transaction {
+            type_,
+            client: (op.client % 8) as ClientID,
+            tx: op.tx as TransactionID,
+            amount: op.amount.map(u128::from),
+            // two currencies, so the per-currency ledgers are exercised as well
+            currency: if op.type_ / 11 % 2 == 0 { Currency::DEFAULT } else { Currency::new("USD").unwrap() },
+            // transfers to the source itself are included, they have to be rejected
+            destination: Some((op.destination % 8) as ClientID),
+            fee: 0,
+            charged_back: 0,
+            timestamp: op.day.map(|day| u64::from(day) * SECONDS_PER_DAY),
+        }
+    }
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    runtime.block_on(async {
+        let account_store = Arc::new(Mutex::new(InMemoryKVStore::<AccountKey, Account>::new().unwrap()));
+        let tx_store = Arc::new(Mutex::new(
+            InMemoryKVStore::<TransactionID, Transaction>::new().unwrap(),
+        ));
+        // one of the clients is the house account, so fees paid to and refunded by it are exercised as well
+        let fees = "house = 7\nrefund_on_chargeback = true\nwithdrawal = 0.0001 + 1%\ndeposit.USD = 0.5% max 1\n";
+        let mut mgr = Manager::new(account_store.clone(), tx_store)
+            .with_verify_mode(VerifyMode::EachTransaction)
+            .with_fees(fees.parse().unwrap())
+            // authorizations expire quickly and disputes have short windows and deadlines, so expiries happen within
+            // short inputs, the amounts of disputes count and withdrawals are claimed
+            .with_policy(Policy {
+                authorization_expiry: 4,
+                dispute_window: 30,
+                dispute_deadline: 5,
+                dispute_tracking: DisputeTracking::Transaction,
+                dispute_amounts: DisputeAmounts::Partial,
+                withdrawal_disputes: WithdrawalDisputes::Claim,
+                ..Default::default()
+            });
+
+        for op in &ops {
+            if let Err(Error::InvariantViolation(reason)) = mgr.process_transaction(op.into()).await {
+                panic!("{}", reason);
+            }
+        }
+
+        let accounts = account_store.lock().await.clone();
+        verify::check_accounts(accounts.into_iter().map(|(_, a)| a), mgr.ledgers()).unwrap();
+    });
+});
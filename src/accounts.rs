@@ -1,59 +1,366 @@
+use std::collections::{BTreeSet, HashMap};
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use tracing::{info, warn};
 
 use crate::{
+    assets::Assets,
     error::{Error, Result},
+    fees::FeeSchedule,
+    hooks::{Hooks, Observer, Validator},
+    limits::{CountedWithdrawal, Limits, LONGEST_WINDOW},
+    policy::{DisputeAmounts, DisputeHold, DisputeTracking, LockOn, Policy, WithdrawalChargebacks, WithdrawalDisputes},
     storage::KVStore,
-    types::{Account, ClientID, Transaction, TransactionID, TxType},
+    types::{
+        Account, AccountKey, ChargedBack, ClientID, Currency, Hold, HoldReason, Timestamp, Transaction, TransactionID,
+        TxType, SECONDS_PER_DAY,
+    },
+    verify::{self, Ledger, Ledgers, VerifyMode},
 };
 
+// signed converts a transaction amount into the representation of the balances
+fn signed(amount: u128) -> Result<i128> {
+    i128::try_from(amount).map_err(|_| Error::Overflow)
+}
+
+// share returns the part of the value that corresponds to the part of the whole, rounded down
+fn share(value: u128, part: u128, whole: u128) -> u128 {
+    if part >= whole {
+        return value;
+    }
+    value / whole * part + (value % whole).saturating_mul(part) / whole
+}
+
+// Applied describes what an applied transaction did besides changing the balances of its client
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Applied {
+    // the currency the transaction was applied in
+    pub currency: Currency,
+    // the fee posted to the house account
+    pub fee: u128,
+    // the fee of a charged back transaction that the house account paid back
+    pub refunded_fee: u128,
+    // the reasons the validators flagged the transaction for
+    pub flags: Vec<String>,
+}
+
 // This account manager processes all transactions and updates the accounts
 // it's generic over the storage types for the accounts and for the transactions
 #[derive(Debug)]
 pub struct Manager<A, T>
 where
-    A: KVStore<Key = ClientID, Value = Account>,
+    A: KVStore<Key = AccountKey, Value = Account>,
     T: KVStore<Key = TransactionID, Value = Transaction>,
 {
     accounts: Arc<Mutex<A>>,
     transactions: Arc<Mutex<T>>,
+    verify_mode: VerifyMode,
+    policy: Policy,
+    fees: FeeSchedule,
+    limits: Limits,
+    assets: Assets,
+    ledgers: Ledgers,
+    // sum of the totals of all accounts per currency, kept up to date to check the conservation after each transaction
+    sums_of_totals: HashMap<Currency, i128>,
+    // the number of transactions processed so far, it's the clock of the expiry of holds
+    seq: u64,
+    // the open holds by the id of the transaction they are held back for, and the ones that expire ordered by their expiry
+    holds: HashMap<TransactionID, Hold>,
+    expiries: BTreeSet<(u64, TransactionID)>,
+    // the latest time the input has told so far, and the disputes that are resolved once it's past their deadline
+    clock: Option<Timestamp>,
+    deadlines: BTreeSet<(Timestamp, TransactionID)>,
+    // the time and amount of the withdrawals of the accounts whose limits count them, within the longest window
+    withdrawals: HashMap<AccountKey, Vec<(Timestamp, u128)>>,
+    // the parts of the stored transactions that were charged back without a hold keeping track of them, by the id
+    // of the transaction, so a snapshot can persist them
+    charged_back: HashMap<TransactionID, u128>,
+    hooks: Hooks,
+    // whether the transaction that is processed is known to be rejected, see `skip_transaction`
+    skipping: bool,
 }
 
 impl<A, T> Manager<A, T>
 where
-    A: KVStore<Key = ClientID, Value = Account>,
+    A: KVStore<Key = AccountKey, Value = Account>,
     T: KVStore<Key = TransactionID, Value = Transaction>,
 {
     pub fn new(account_store: Arc<Mutex<A>>, tx_store: Arc<Mutex<T>>) -> Self {
         Self {
             accounts: account_store,
             transactions: tx_store,
+            verify_mode: VerifyMode::Off,
+            policy: Policy::default(),
+            fees: FeeSchedule::default(),
+            limits: Limits::default(),
+            assets: Assets::default(),
+            ledgers: Ledgers::new(),
+            sums_of_totals: HashMap::new(),
+            seq: 0,
+            holds: HashMap::new(),
+            expiries: BTreeSet::new(),
+            clock: None,
+            deadlines: BTreeSet::new(),
+            withdrawals: HashMap::new(),
+            charged_back: HashMap::new(),
+            hooks: Hooks::new(),
+            skipping: false,
+        }
+    }
+
+    // with_verify_mode enables checking the account invariants after every transaction or at the end
+    pub fn with_verify_mode(mut self, verify_mode: VerifyMode) -> Self {
+        self.verify_mode = verify_mode;
+        self
+    }
+
+    pub fn verify_mode(&self) -> VerifyMode {
+        self.verify_mode
+    }
+
+    // with_policy sets the business rules for the edge cases of disputes and chargebacks
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    // with_fees sets the fees charged on deposits and withdrawals
+    pub fn with_fees(mut self, fees: FeeSchedule) -> Self {
+        self.fees = fees;
+        self
+    }
+
+    // with_limits sets the risk limits on withdrawals
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // with_hooks sets the validators and observers, replacing the ones added before
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    // with_validator adds a validator that is asked about every transaction before it's applied, after the
+    // ones added before
+    pub fn with_validator<V: Validator + 'static>(mut self, validator: V) -> Self {
+        self.hooks = self.hooks.with_validator(validator);
+        self
+    }
+
+    // with_observer adds an observer that is told about every account change, after the ones added before
+    pub fn with_observer<O: Observer + 'static>(mut self, observer: O) -> Self {
+        self.hooks = self.hooks.with_observer(observer);
+        self
+    }
+
+    // with_assets sets the precision of the currencies, the fees are rounded to it
+    pub fn with_assets(mut self, assets: Assets) -> Self {
+        self.assets = assets;
+        self
+    }
+
+    // with_sequence sets the number of transactions that were processed before, e.g. when replaying from a snapshot
+    pub fn with_sequence(mut self, seq: u64) -> Self {
+        self.seq = seq;
+        self
+    }
+
+    // with_clock sets the latest time of the transactions that were processed before, e.g. when replaying from a snapshot
+    pub fn with_clock(mut self, clock: Option<Timestamp>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // clock returns the latest time of the transactions processed so far, if any of them had one
+    pub fn clock(&self) -> Option<Timestamp> {
+        self.clock
+    }
+
+    // with_holds restores the holds that were open before, e.g. when replaying from a snapshot
+    pub fn with_holds<H: IntoIterator<Item = Hold>>(mut self, holds: H) -> Self {
+        for hold in holds {
+            self.open(hold);
+        }
+        self
+    }
+
+    // with_withdrawals restores the withdrawals that counted against the limits before, e.g. when replaying from a snapshot
+    pub fn with_withdrawals<W: IntoIterator<Item = CountedWithdrawal>>(mut self, withdrawals: W) -> Self {
+        for withdrawal in withdrawals {
+            self.withdrawals
+                .entry((withdrawal.client, withdrawal.currency))
+                .or_default()
+                .push((withdrawal.timestamp, withdrawal.amount));
+        }
+        self
+    }
+
+    // withdrawals returns the withdrawals of all accounts that still count against their limits
+    pub fn withdrawals(&self) -> impl Iterator<Item = CountedWithdrawal> + '_ {
+        self.withdrawals.iter().flat_map(|(&(client, currency), withdrawals)| {
+            withdrawals.iter().map(move |&(timestamp, amount)| CountedWithdrawal {
+                client,
+                currency,
+                timestamp,
+                amount,
+            })
+        })
+    }
+
+    // chargebacks returns the parts of the stored transactions that were charged back without a hold keeping track of them
+    pub fn chargebacks(&self) -> impl Iterator<Item = ChargedBack> + '_ {
+        self.charged_back.iter().map(|(&tx, &amount)| ChargedBack { tx, amount })
+    }
+
+    // holds returns the open holds of all accounts
+    pub fn holds(&self) -> impl Iterator<Item = &Hold> {
+        self.holds.values()
+    }
+
+    // ledgers returns the volumes per currency that were moved by all transactions processed so far
+    pub fn ledgers(&self) -> &Ledgers {
+        &self.ledgers
+    }
+
+    // skip_transaction takes a transaction that is known to be rejected, e.g. by a validator of the run a history
+    // is replayed from, into account without applying it: it's processed up to the validators, so it's counted, stored
+    // and creates the accounts it looks up like any rejected transaction, and then it's rejected whatever they say
+    pub async fn skip_transaction(&mut self, tx: Transaction) -> Result<()> {
+        self.skipping = true;
+        let res = self.process_transaction(tx).await;
+        self.skipping = false;
+        match res {
+            Ok(_) => unreachable!("a skipped transaction is never applied"),
+            Err(_) => Ok(()),
+        }
+    }
+
+    // validate asks the validators about the transaction on the account of its client
+    fn validate(&mut self, tx: &Transaction, account: &Account) -> Result<Vec<String>> {
+        if self.skipping {
+            return Err(Error::Rejected("skipped".to_string()));
+        }
+        self.hooks.validate(tx, account)
+    }
+
+    // receive does what happens to every transaction before it's checked, whether it's applied or rejected later on
+    async fn receive(&mut self, tx: &mut Transaction) -> Result<()> {
+        self.seq += 1;
+        // the clock never goes back, a transaction that is late in the input doesn't reopen expired disputes
+        // and a transaction without a timestamp happens at the time of the latest one
+        match tx.timestamp {
+            Some(timestamp) => self.clock = Some(self.clock.map_or(timestamp, |clock| clock.max(timestamp))),
+            None => tx.timestamp = self.clock,
         }
+        self.expire_holds().await?;
+        // store the transaction if it moves money, so it can be disputed later on
+        // a transfer is only stored once it's applied, a rejected one moved no money that a chargeback could reverse
+        if tx.type_.is_disputable() && tx.type_ != TxType::Transfer {
+            self.transactions.lock().await.set(tx.tx, tx.clone())?;
+            self.charged_back.remove(&tx.tx);
+        }
+        Ok(())
     }
 
     // process_transaction implements the main business logic of this application
-    pub async fn process_transaction(&mut self, tx: Transaction) -> Result<()> {
-        let mut account = self.get_account(tx.client).await?;
-        if account.locked {
+    #[tracing::instrument(level = "trace", skip_all, fields(tx = tx.tx, client = tx.client))]
+    pub async fn process_transaction(&mut self, mut tx: Transaction) -> Result<Applied> {
+        self.receive(&mut tx).await?;
+        let source_tx = match tx.type_ {
+            TxType::Dispute | TxType::Resolve | TxType::Chargeback => {
+                // unless the policy allows partial disputes the whole transaction is disputed, whatever the amount says
+                if self.policy.dispute_amounts == DisputeAmounts::Ignore {
+                    tx.amount = None;
+                }
+                self.transactions.lock().await.get(tx.tx).ok().cloned()
+            }
+            _ => None,
+        };
+        // the amount of a partial dispute, resolve or chargeback is given in the currency of the referenced transaction
+        if let Some(ref source_tx) = source_tx {
+            if tx.amount.is_some() && tx.currency != source_tx.currency {
+                return Err(Error::InvalidCurrency(tx.currency.to_string()));
+            }
+        }
+        // transfers and the disputes on them touch two accounts, so they are processed on their own
+        match source_tx {
+            Some(ref transfer) if transfer.type_ == TxType::Transfer => return self.process_transfer(&tx, transfer).await,
+            _ if tx.type_ == TxType::Transfer => return self.process_transfer(&tx, &tx).await,
+            _ => {}
+        }
+        // captures and voids refer to an open authorization of the same client, releases to a compliance hold
+        let hold = match tx.type_ {
+            TxType::Capture | TxType::Void => self.hold_of(tx.tx, HoldReason::Authorization, tx.client),
+            TxType::Release => self.hold_of(tx.tx, HoldReason::Compliance, tx.client),
+            _ => None,
+        };
+        // the dispute family acts on the currency of the referenced transaction, whatever the row says, and so do
+        // the transactions that refer to a hold on the currency of the hold
+        // if there is no such transaction, the account is still looked up (and created) before the dispute is rejected
+        let currency = source_tx
+            .map(|source_tx| source_tx.currency)
+            .or(hold.as_ref().map(|hold| hold.currency))
+            .unwrap_or(tx.currency);
+        let before = self.get_account(tx.client, currency).await?;
+        let mut account = before.clone();
+        if account.closed {
+            return Err(Error::AccountClosed);
+        }
+        // the policy decides which transactions are still accepted on a locked account
+        if account.locked && !self.policy.locked_accepts.accepts(&tx.type_) {
             return Err(Error::AccountLocked);
         }
+        let flags = self.validate(&tx, &account)?;
+        let mut ledger = self.ledgers.get(&currency).cloned().unwrap_or_default();
+        let mut applied = Applied {
+            currency,
+            flags,
+            ..Applied::default()
+        };
+        // the holds opened or changed and closed by the transaction, they are only updated once it's applied
+        let (mut changed, mut closed) = (None, None);
+        // the charged back transaction with the part charged back so far, if no hold keeps track of it
+        let mut charged_back = None;
+        // the amount that leaves the account and counts against its limits
+        let mut counted = None;
         match tx.type_ {
-            // Deposit -> add the amount to the balance
+            // Deposit -> add the amount to the balance, less the fee
             TxType::Deposit => {
                 if let Some(amount) = tx.amount {
+                    // the fee is taken from the deposit, so it can't be more than the deposit
+                    applied.fee = self.fee(tx.client, &tx.type_, currency, amount).min(amount);
+                    let amount = signed(amount)?;
+                    // total is never smaller than available, so checking it is enough
+                    account.total = account.total.checked_add(amount).ok_or(Error::Overflow)?;
                     account.available += amount;
-                    account.total += amount;
+                    ledger.deposited = ledger.deposited.checked_add(amount as u128).ok_or(Error::Overflow)?;
                 }
             }
 
             // Withdraw -> subtract the amount from the balance
             TxType::Withdrawal => {
+                if account.frozen {
+                    return Err(Error::AccountFrozen);
+                }
                 if let Some(amount) = tx.amount {
-                    if account.available < amount {
+                    self.check_limits(&tx, currency, amount)?;
+                    counted = Some(amount);
+                    // the fee is paid on top of the withdrawal
+                    applied.fee = self.fee(tx.client, &tx.type_, currency, amount);
+                    let needed = signed(amount.checked_add(applied.fee).ok_or(Error::Overflow)?)?;
+                    if account.available < needed {
                         return Err(Error::InsufficientFunds);
                     }
+                    let amount = signed(amount)?;
                     account.available -= amount;
                     account.total -= amount;
+                    ledger.withdrawn = ledger.withdrawn.checked_add(amount as u128).ok_or(Error::Overflow)?;
                 }
             }
 
@@ -65,13 +372,28 @@ where
                 if let Some(amount) = source_tx.amount {
                     // we can only held money back that is still in our system
                     if source_tx.type_ == TxType::Deposit {
-                        // we can only hold back as much money as there is in the account
-                        let mut amount = amount;
-                        if amount > account.available {
-                            amount = account.available;
+                        match self.policy.dispute_tracking {
+                            DisputeTracking::Account => {
+                                self.check_window(&tx, source_tx)?;
+                                self.hold_disputed(&mut account, amount)?;
+                            }
+                            DisputeTracking::Transaction => {
+                                let (mut hold, amount) = self.open_dispute(&tx, source_tx, account.id)?;
+                                hold.amount += self.hold_disputed(&mut account, amount)?;
+                                changed = Some(hold);
+                            }
+                        }
+                    } else {
+                        match self.policy.withdrawal_disputes {
+                            // the withdrawn money left our system, so the client can only claim it back
+                            WithdrawalDisputes::Claim => {
+                                let (hold, amount) = self.open_dispute(&tx, source_tx, account.id)?;
+                                account.pending_credit = account.pending_credit.checked_add(signed(amount)?).ok_or(Error::Overflow)?;
+                                changed = Some(hold);
+                            }
+                            WithdrawalDisputes::Ignore => {}
+                            WithdrawalDisputes::Reject => return Err(Error::NotPermitted),
                         }
-                        account.held += amount;
-                        account.available -= amount;
                     }
                 }
             }
@@ -83,76 +405,731 @@ where
                 if let Some(amount) = source_tx.amount {
                     // we can release money back that is still in our system
                     if source_tx.type_ == TxType::Deposit {
-                        // we can only hold back as much money as there is in the account
-                        let mut amount = amount;
-                        if amount > account.held {
-                            amount = account.held;
+                        match self.policy.dispute_tracking {
+                            // we can only release as much money as is held back for disputes
+                            DisputeTracking::Account => {
+                                let amount = signed(amount)?.min(account.held_dispute);
+                                account.release(HoldReason::Dispute, amount);
+                            }
+                            // we can only release as much money as the dispute held back
+                            DisputeTracking::Transaction => {
+                                let (hold, _, freed) = self.settle_dispute(&tx, tx.tx, tx.client)?;
+                                account.release(HoldReason::Dispute, signed(freed)?);
+                                changed = Some(hold);
+                            }
+                        }
+                    } else {
+                        match self.policy.withdrawal_disputes {
+                            // the claim is discarded
+                            WithdrawalDisputes::Claim => {
+                                let (hold, amount, _) = self.settle_dispute(&tx, tx.tx, tx.client)?;
+                                account.pending_credit -= signed(amount)?;
+                                changed = Some(hold);
+                            }
+                            WithdrawalDisputes::Ignore => {}
+                            WithdrawalDisputes::Reject => return Err(Error::NotPermitted),
                         }
-                        account.held -= amount;
-                        account.available += amount;
                     }
                 }
             }
 
             // Chargeback -> the referenced transaction should be reversed
             // if the disputed transaction is a deposit, the amount in question is finally subtracted from the held balance
-            // if the disputed transaction is a withdrawal, the amount in question is added to the available balance from thin air
-            // (The assumption is that disputes and chargebacks are always executed in matching pairs so that no balances are created or destroyed)
+            // if the disputed transaction is a withdrawal, by default the claimed amount is added to the available balance from thin air
             TxType::Chargeback => {
                 let tx_store = self.transactions.lock().await;
                 let source_tx = tx_store.get(tx.tx)?;
                 if let Some(amount) = source_tx.amount {
                     // we can only held money back that is still in our system
                     if source_tx.type_ == TxType::Deposit {
-                        let mut amount = amount;
-                        if amount > account.held {
-                            // we can only take as money as we find in the account
-                            amount = account.held;
-                        }
-                        account.held -= amount;
-                        account.total -= amount;
-                        account.locked = true;
+                        let freed = match self.policy.dispute_tracking {
+                            // we can only take as much money as is held back for disputes
+                            // the fee is refunded for the part that wasn't charged back before
+                            DisputeTracking::Account => {
+                                let freed = amount.min(account.held_dispute as u128);
+                                let total = source_tx.charged_back.saturating_add(freed).min(amount);
+                                applied.refunded_fee = self.refunded_fee(source_tx, source_tx.charged_back, total);
+                                charged_back = Some(Transaction { charged_back: total, ..source_tx.clone() });
+                                freed
+                            }
+                            // we can only take as much money as the dispute held back
+                            DisputeTracking::Transaction => {
+                                let (hold, charged_back, freed) = self.settle_dispute(&tx, tx.tx, tx.client)?;
+                                applied.refunded_fee =
+                                    self.refunded_fee(source_tx, hold.charged_back - charged_back, hold.charged_back);
+                                changed = Some(hold);
+                                freed
+                            }
+                        };
+                        account.take(HoldReason::Dispute, signed(freed)?);
+                        account.locked |= self.policy.lock_on != LockOn::Never;
+                        ledger.charged_back = ledger.charged_back.checked_add(freed).ok_or(Error::Overflow)?;
                     } else if source_tx.type_ == TxType::Withdrawal {
+                        if self.policy.withdrawal_chargebacks == WithdrawalChargebacks::Reject {
+                            return Err(Error::NotPermitted);
+                        }
+                        // the chargeback settles the claim of the client, without claims it doesn't need a dispute
+                        // and a withdrawal can be charged back in parts as well
+                        let (amount, range) = match self.policy.withdrawal_disputes {
+                            WithdrawalDisputes::Claim => {
+                                let (hold, amount, _) = self.settle_dispute(&tx, tx.tx, tx.client)?;
+                                account.pending_credit -= signed(amount)?;
+                                let range = (hold.charged_back - amount, hold.charged_back);
+                                changed = Some(hold);
+                                (amount, range)
+                            }
+                            // the fee is refunded for the part that wasn't charged back before
+                            _ => {
+                                let part = match tx.amount {
+                                    Some(part) if part > amount => return Err(Error::ExceedsDisputed),
+                                    Some(part) => part,
+                                    None => amount,
+                                };
+                                let total = source_tx.charged_back.saturating_add(part).min(amount);
+                                charged_back = Some(Transaction { charged_back: total, ..source_tx.clone() });
+                                (part, (source_tx.charged_back, total))
+                            }
+                        };
                         // the withdrawal should be reversed, so we increase the available amount
-                        // the account is NOT locked since here the account holder is the disadvantaged party of the dispute
-                        account.available += amount;
-                        account.total += amount;
+                        if self.policy.withdrawal_chargebacks == WithdrawalChargebacks::Credit {
+                            applied.refunded_fee = self.refunded_fee(source_tx, range.0, range.1);
+                            let amount = signed(amount)?;
+                            account.total = account.total.checked_add(amount).ok_or(Error::Overflow)?;
+                            account.available += amount;
+                            ledger.reversed = ledger.reversed.checked_add(amount as u128).ok_or(Error::Overflow)?;
+                        }
+                        // by default the account is NOT locked since here the account holder is the disadvantaged party of the dispute
+                        account.locked |= self.policy.lock_on == LockOn::AnyChargeback;
+                    }
+                }
+            }
+
+            // Transfer -> processed on its own, see `process_transfer`
+            TxType::Transfer => unreachable!("transfers are processed on their own"),
+
+            // Authorize -> reserve the amount for a later capture, it's not available anymore but still part of the total
+            TxType::Authorize => {
+                if account.frozen {
+                    return Err(Error::AccountFrozen);
+                }
+                // an open hold can't be replaced, it would never be released
+                if self.holds.contains_key(&tx.tx) {
+                    return Err(Error::NotPermitted);
+                }
+                if let Some(amount) = tx.amount {
+                    // the authorization is checked against the limits like a withdrawal of the whole amount, but only
+                    // the amount that is captured counts against them
+                    self.check_limits(&tx, currency, amount)?;
+                    let reserved = signed(amount)?;
+                    if account.available < reserved {
+                        return Err(Error::InsufficientFunds);
+                    }
+                    account.hold(HoldReason::Authorization, reserved)?;
+                    changed = Some(Hold {
+                        tx: tx.tx,
+                        reason: HoldReason::Authorization,
+                        client: tx.client,
+                        currency,
+                        amount,
+                        disputed: 0,
+                        charged_back: 0,
+                        deadline: None,
+                        expires: match self.policy.authorization_expiry {
+                            0 => None,
+                            expiry => Some(self.seq.saturating_add(expiry)),
+                        },
+                    });
+                }
+            }
+
+            // Capture -> withdraw the captured amount from the reservation and release the rest of it
+            TxType::Capture => {
+                if account.frozen {
+                    return Err(Error::AccountFrozen);
+                }
+                let hold = hold.as_ref().ok_or(Error::NotFound)?;
+                // without an amount the full reservation is captured
+                let captured = tx.amount.unwrap_or(hold.amount);
+                if captured > hold.amount {
+                    return Err(Error::ExceedsAuthorization);
+                }
+                // a capture is a withdrawal of the captured amount, so it pays the fee of one on top of it, out of the
+                // available balance once the rest of the reservation is released
+                applied.fee = self.fee(tx.client, &TxType::Withdrawal, currency, captured);
+                counted = Some(captured);
+                let (reserved, captured) = (signed(hold.amount)?, signed(captured)?);
+                if account.available + reserved - captured < signed(applied.fee)? {
+                    return Err(Error::InsufficientFunds);
+                }
+                account.take(HoldReason::Authorization, captured);
+                account.release(HoldReason::Authorization, reserved - captured);
+                ledger.withdrawn = ledger.withdrawn.checked_add(captured as u128).ok_or(Error::Overflow)?;
+                closed = Some(tx.tx);
+            }
+
+            // Void -> release the reservation
+            TxType::Void => {
+                let hold = hold.as_ref().ok_or(Error::NotFound)?;
+                account.release(HoldReason::Authorization, signed(hold.amount)?);
+                closed = Some(tx.tx);
+            }
+
+            // Unlock -> an operator lifts the lock of a chargeback
+            TxType::Unlock => account.locked = false,
+
+            // Freeze -> an operator stops all withdrawals from a suspicious account, until it's unfrozen again
+            TxType::Freeze => account.frozen = true,
+            TxType::Unfreeze => account.frozen = false,
+
+            // Close -> the account is shut down for good, which is only possible once all money is paid out
+            TxType::Close => {
+                if account.total != 0 || account.held != 0 {
+                    return Err(Error::AccountNotEmpty);
+                }
+                account.closed = true;
+            }
+
+            // Hold -> an operator holds back an amount of the account for compliance reasons, e.g. while a source of funds is checked
+            TxType::Hold => {
+                if self.holds.contains_key(&tx.tx) {
+                    return Err(Error::NotPermitted);
+                }
+                if let Some(amount) = tx.amount {
+                    let held = signed(amount)?;
+                    if account.available < held {
+                        return Err(Error::InsufficientFunds);
+                    }
+                    account.hold(HoldReason::Compliance, held)?;
+                    changed = Some(Hold {
+                        tx: tx.tx,
+                        reason: HoldReason::Compliance,
+                        client: tx.client,
+                        currency,
+                        amount,
+                        expires: None,
+                        disputed: 0,
+                        charged_back: 0,
+                        deadline: None,
+                    });
+                }
+            }
+
+            // Release -> the compliance hold is lifted again
+            TxType::Release => {
+                let hold = hold.as_ref().ok_or(Error::NotFound)?;
+                account.release(HoldReason::Compliance, signed(hold.amount)?);
+                closed = Some(tx.tx);
+            }
+        }
+        let mut changes = vec![(before, account)];
+        // the fee moves from the client to the house account, a refunded fee the other way round
+        if applied.fee > 0 || applied.refunded_fee > 0 {
+            let house_before = self.get_account(self.fees.house, currency).await?;
+            let mut house = house_before.clone();
+            let amount = signed(applied.fee)? - signed(applied.refunded_fee)?;
+            let (_, account) = &mut changes[0];
+            account.available -= amount;
+            account.total -= amount;
+            house.total = house.total.checked_add(amount).ok_or(Error::Overflow)?;
+            house.available += amount;
+            ledger.fees = ledger.fees.checked_add(applied.fee).ok_or(Error::Overflow)?.saturating_sub(applied.refunded_fee);
+            changes.push((house_before, house));
+        }
+        self.commit(&tx, currency, ledger, changes).await?;
+        self.update_holds(changed, closed);
+        if let Some(amount) = counted {
+            self.count_withdrawal(&tx, currency, amount);
+        }
+        if let Some(source_tx) = charged_back {
+            self.charged_back.insert(source_tx.tx, source_tx.charged_back);
+            self.transactions.lock().await.set(source_tx.tx, source_tx)?;
+        }
+        // the fee is recorded with the transaction, so a chargeback can refund it
+        // a capture isn't stored, it can't be disputed and its fee is never refunded
+        if applied.fee > 0 && tx.type_.is_disputable() {
+            self.transactions.lock().await.set(
+                tx.tx,
+                Transaction {
+                    fee: applied.fee,
+                    ..tx
+                },
+            )?;
+        }
+        Ok(applied)
+    }
+
+    // process_transfer applies a transfer, or a dispute, resolve or chargeback of the given transfer
+    // The destination received the money, so it's the account that holds it back while the transfer is disputed.
+    // A chargeback reverses both legs by moving the held back money to the source again.
+    async fn process_transfer(&mut self, tx: &Transaction, transfer: &Transaction) -> Result<Applied> {
+        let currency = transfer.currency;
+        let destination = match transfer.destination {
+            Some(destination) if destination != transfer.client => destination,
+            _ => return Err(Error::InvalidTransfer),
+        };
+        // a transfer is disputed, resolved and charged back by its source, who paid it like the client of a deposit,
+        // for every other client it's a transaction they don't have
+        if tx.type_ != TxType::Transfer && tx.client != transfer.client {
+            return Err(Error::NotFound);
+        }
+        let source_before = self.get_account(transfer.client, currency).await?;
+        let destination_before = self.get_account(destination, currency).await?;
+        if source_before.closed || destination_before.closed {
+            return Err(Error::AccountClosed);
+        }
+        let (mut source, mut destination) = (source_before.clone(), destination_before.clone());
+        // transfers are never accepted on locked accounts, the dispute family only if the policy accepts it
+        // on the accounts it changes
+        let locked = match tx.type_ {
+            TxType::Transfer | TxType::Chargeback => source.locked || destination.locked,
+            _ => destination.locked,
+        };
+        if locked && !self.policy.locked_accepts.accepts(&tx.type_) {
+            return Err(Error::AccountLocked);
+        }
+        // the validators get the account of the client of the transaction, the source of a transfer
+        let flags = match tx.client == destination.id {
+            true => self.validate(tx, &destination)?,
+            false => self.validate(tx, &source)?,
+        };
+        let mut ledger = self.ledgers.get(&currency).cloned().unwrap_or_default();
+        let mut changed = None;
+        if let Some(amount) = transfer.amount {
+            match tx.type_ {
+                // Transfer -> move the amount from the available balance of the source to the destination
+                TxType::Transfer => {
+                    if source.frozen {
+                        return Err(Error::AccountFrozen);
+                    }
+                    let amount = signed(amount)?;
+                    if source.available < amount {
+                        return Err(Error::InsufficientFunds);
+                    }
+                    destination.total = destination.total.checked_add(amount).ok_or(Error::Overflow)?;
+                    destination.available += amount;
+                    source.available -= amount;
+                    source.total -= amount;
+                    ledger.transferred = ledger.transferred.checked_add(amount as u128).ok_or(Error::Overflow)?;
+                }
+
+                // Dispute -> the destination holds the amount back, like for a disputed deposit
+                TxType::Dispute => match self.policy.dispute_tracking {
+                    DisputeTracking::Account => {
+                        self.check_window(tx, transfer)?;
+                        self.hold_disputed(&mut destination, amount)?;
+                    }
+                    DisputeTracking::Transaction => {
+                        let (mut hold, amount) = self.open_dispute(tx, transfer, destination.id)?;
+                        hold.amount += self.hold_disputed(&mut destination, amount)?;
+                        changed = Some(hold);
                     }
+                },
+
+                // Resolve -> the destination gets the held back amount available again
+                TxType::Resolve => {
+                    let freed = match self.policy.dispute_tracking {
+                        DisputeTracking::Account => amount.min(destination.held_dispute as u128),
+                        DisputeTracking::Transaction => {
+                            let (hold, _, freed) = self.settle_dispute(tx, transfer.tx, destination.id)?;
+                            changed = Some(hold);
+                            freed
+                        }
+                    };
+                    destination.release(HoldReason::Dispute, signed(freed)?);
+                }
+
+                // Chargeback -> the held back amount goes back to the source
+                TxType::Chargeback => {
+                    let freed = match self.policy.dispute_tracking {
+                        DisputeTracking::Account => amount.min(destination.held_dispute as u128),
+                        DisputeTracking::Transaction => {
+                            let (hold, _, freed) = self.settle_dispute(tx, transfer.tx, destination.id)?;
+                            changed = Some(hold);
+                            freed
+                        }
+                    };
+                    let amount = signed(freed)?;
+                    destination.take(HoldReason::Dispute, amount);
+                    source.total = source.total.checked_add(amount).ok_or(Error::Overflow)?;
+                    source.available += amount;
+                    // the destination didn't bring the money into the system, so it's only locked if any chargeback locks
+                    destination.locked |= self.policy.lock_on == LockOn::AnyChargeback;
+                }
+
+                ref type_ => unreachable!("{:?} is not part of a transfer", type_),
+            }
+        }
+        self.commit(tx, currency, ledger, vec![(source_before, source), (destination_before, destination)])
+            .await?;
+        self.update_holds(changed, None);
+        if tx.type_ == TxType::Transfer {
+            self.transactions.lock().await.set(tx.tx, tx.clone())?;
+        }
+        // transfers are free
+        Ok(Applied {
+            currency,
+            flags,
+            ..Applied::default()
+        })
+    }
+
+    // fee returns the fee of a deposit or withdrawal of the client, the house account doesn't pay fees to itself
+    fn fee(&self, client: ClientID, type_: &TxType, currency: Currency, amount: u128) -> u128 {
+        if client == self.fees.house {
+            return 0;
+        }
+        self.fees.fee(&self.assets, type_, currency, amount)
+    }
+
+    // check_limits returns `LimitExceeded` if a withdrawal of the amount by the transaction breaks a limit of the account
+    fn check_limits(&self, tx: &Transaction, currency: Currency, amount: u128) -> Result<()> {
+        let withdrawals = self.withdrawals.get(&(tx.client, currency)).map_or(&[][..], Vec::as_slice);
+        let now = tx.timestamp.unwrap_or_default();
+        self.limits.check(&self.assets, tx.client, currency, amount, now, withdrawals)
+    }
+
+    // count_withdrawal remembers the applied withdrawal or capture for the limits of its account that count the withdrawals
+    // before, and forgets the ones that are too old to count against any of them
+    fn count_withdrawal(&mut self, tx: &Transaction, currency: Currency, amount: u128) {
+        if !self.limits.counts_withdrawals(tx.client) {
+            return;
+        }
+        let now = self.clock.unwrap_or_default();
+        let withdrawals = self.withdrawals.entry((tx.client, currency)).or_default();
+        withdrawals.retain(|(time, _)| time.saturating_add(LONGEST_WINDOW) > now);
+        withdrawals.push((tx.timestamp.unwrap_or_default(), amount));
+    }
+
+    // refunded_fee returns the part of the fee of the charged back transaction the fee schedule refunds, when the
+    // charged back amount of it goes from `from` to `to`
+    // the fee is refunded in proportion to the charged back amount, so charging a transaction back in parts refunds
+    // the same fee as charging it back at once
+    fn refunded_fee(&self, source_tx: &Transaction, from: u128, to: u128) -> u128 {
+        if !self.fees.refund_on_chargeback {
+            return 0;
+        }
+        let amount = source_tx.amount.unwrap_or_default();
+        share(source_tx.fee, to, amount).saturating_sub(share(source_tx.fee, from, amount))
+    }
+
+    // hold_of returns the open hold for the transaction with the given id, if it's held back for the reason on the account of the client
+    fn hold_of(&self, id: TransactionID, reason: HoldReason, client: ClientID) -> Option<Hold> {
+        self.holds
+            .get(&id)
+            .filter(|hold| hold.reason == reason && hold.client == client)
+            .cloned()
+    }
+
+    // update_holds keeps track of the hold opened or changed by a transaction and forgets the one it closed,
+    // a dispute is closed as soon as it's settled
+    fn update_holds(&mut self, changed: Option<Hold>, closed: Option<TransactionID>) {
+        if let Some(id) = closed {
+            self.close(id);
+        }
+        match changed {
+            Some(hold) if hold.settled() => self.close(hold.tx),
+            Some(hold) => self.open(hold),
+            None => {}
+        }
+    }
+
+    // open keeps track of the hold, replacing the one of the same transaction
+    fn open(&mut self, hold: Hold) {
+        self.close(hold.tx);
+        if let Some(expires) = hold.expires {
+            self.expiries.insert((expires, hold.tx));
+        }
+        if let Some(deadline) = hold.deadline {
+            self.deadlines.insert((deadline, hold.tx));
+        }
+        self.holds.insert(hold.tx, hold);
+    }
+
+    fn close(&mut self, id: TransactionID) {
+        if let Some(hold) = self.holds.remove(&id) {
+            if let Some(expires) = hold.expires {
+                self.expiries.remove(&(expires, id));
+            }
+            if let Some(deadline) = hold.deadline {
+                self.deadlines.remove(&(deadline, id));
+            }
+        }
+    }
+
+    // expire_holds releases the holds that expired before the current transaction and resolves the disputes that
+    // are open past their deadline
+    async fn expire_holds(&mut self) -> Result<()> {
+        while let Some(&(expires, id)) = self.expiries.first().filter(|(expires, _)| *expires < self.seq) {
+            self.expiries.remove(&(expires, id));
+            let hold = match self.holds.remove(&id) {
+                Some(hold) => hold,
+                None => continue,
+            };
+            let currency = hold.currency;
+            let before = self.get_account(hold.client, currency).await?;
+            let mut account = before.clone();
+            account.release(hold.reason, signed(hold.amount)?);
+            // the expiry acts like the transaction that releases the hold
+            let release = Transaction {
+                type_: match hold.reason {
+                    HoldReason::Dispute => TxType::Resolve,
+                    HoldReason::Authorization => TxType::Void,
+                    HoldReason::Compliance => TxType::Release,
+                },
+                client: hold.client,
+                tx: id,
+                currency,
+                ..Default::default()
+            };
+            let ledger = self.ledgers.get(&currency).cloned().unwrap_or_default();
+            self.commit(&release, currency, ledger, vec![(before, account)]).await?;
+            info!(tx = id, client = hold.client, currency = %currency, reason = hold.reason.as_str(), "hold expired");
+        }
+        while let Some(&(deadline, id)) = self
+            .deadlines
+            .first()
+            .filter(|(deadline, _)| self.clock.is_some_and(|clock| *deadline < clock))
+        {
+            self.deadlines.remove(&(deadline, id));
+            let mut hold = match self.holds.get(&id) {
+                Some(hold) => hold.clone(),
+                None => continue,
+            };
+            let currency = hold.currency;
+            let before = self.get_account(hold.client, currency).await?;
+            let mut account = before.clone();
+            account.release(HoldReason::Dispute, signed(hold.amount)?);
+            // the claim on a disputed withdrawal is discarded
+            let claim = matches!(self.transactions.lock().await.get(id), Ok(source_tx) if source_tx.type_ == TxType::Withdrawal);
+            if claim {
+                account.pending_credit -= signed(hold.disputed)?;
+            }
+            // the deadline acts like a resolve of everything that is still disputed
+            let resolve = Transaction {
+                type_: TxType::Resolve,
+                client: hold.client,
+                tx: id,
+                currency,
+                ..Default::default()
+            };
+            let ledger = self.ledgers.get(&currency).cloned().unwrap_or_default();
+            self.commit(&resolve, currency, ledger, vec![(before, account)]).await?;
+            info!(tx = id, client = hold.client, currency = %currency, disputed = hold.disputed, "dispute resolved past its deadline");
+            (hold.amount, hold.disputed, hold.deadline) = (0, 0, None);
+            self.update_holds(Some(hold), None);
+        }
+        Ok(())
+    }
+
+    // open_dispute disputes the amount of the transaction, by default all of it that isn't disputed or charged back
+    // yet, and returns the dispute hold of the transaction together with the disputed amount
+    // nothing is held back yet, that's up to the caller
+    fn open_dispute(&self, tx: &Transaction, source_tx: &Transaction, client: ClientID) -> Result<(Hold, u128)> {
+        self.check_window(tx, source_tx)?;
+        let (mut hold, undisputed) = match self.holds.get(&source_tx.tx) {
+            // the parts of a transaction can only be disputed on one account
+            Some(hold) if hold.reason == HoldReason::Dispute && hold.client == client => {
+                let undisputed = source_tx.amount.unwrap_or_default().saturating_sub(hold.disputed + hold.charged_back);
+                if undisputed == 0 {
+                    return Err(Error::AlreadyDisputed);
                 }
+                (hold.clone(), undisputed)
+            }
+            Some(_) => return Err(Error::AlreadyDisputed),
+            None => (
+                Hold {
+                    tx: source_tx.tx,
+                    reason: HoldReason::Dispute,
+                    client,
+                    currency: source_tx.currency,
+                    amount: 0,
+                    expires: None,
+                    disputed: 0,
+                    charged_back: 0,
+                    deadline: None,
+                },
+                source_tx.amount.unwrap_or_default(),
+            ),
+        };
+        let amount = tx.amount.unwrap_or(undisputed);
+        if amount > undisputed {
+            return Err(Error::ExceedsUndisputed);
+        }
+        // the deadline starts with the dispute, later disputes of other parts of the transaction don't extend it
+        if hold.disputed == 0 && self.policy.dispute_deadline > 0 {
+            hold.deadline = self.clock.map(|clock| clock.saturating_add(self.policy.dispute_deadline * SECONDS_PER_DAY));
+        }
+        hold.disputed += amount;
+        Ok((hold, amount))
+    }
+
+    // check_window returns `DisputeWindowClosed` if the dispute comes too late for the disputed transaction,
+    // the dispute window is counted from the time of the transaction to the time of the dispute
+    fn check_window(&self, tx: &Transaction, source_tx: &Transaction) -> Result<()> {
+        if let (Some(happened), Some(now)) = (source_tx.timestamp, tx.timestamp) {
+            if self.policy.dispute_window > 0 && now.saturating_sub(happened) > self.policy.dispute_window * SECONDS_PER_DAY {
+                return Err(Error::DisputeWindowClosed);
+            }
+        }
+        Ok(())
+    }
+
+    // settle_dispute resolves or charges back the disputed amount of the transaction, by default all of it, and
+    // returns the dispute hold of the transaction, the settled amount and how much of it was held back
+    // the money held back settles the dispute first, the part the account couldn't cover is settled last
+    fn settle_dispute(&self, tx: &Transaction, id: TransactionID, client: ClientID) -> Result<(Hold, u128, u128)> {
+        let mut hold = self
+            .hold_of(id, HoldReason::Dispute, client)
+            .filter(|hold| hold.disputed > 0)
+            .ok_or(Error::NotDisputed)?;
+        let amount = tx.amount.unwrap_or(hold.disputed);
+        if amount > hold.disputed {
+            return Err(Error::ExceedsDisputed);
+        }
+        let freed = amount.min(hold.amount);
+        hold.amount -= freed;
+        hold.disputed -= amount;
+        if hold.disputed == 0 {
+            hold.deadline = None;
+        }
+        if tx.type_ == TxType::Chargeback {
+            hold.charged_back += amount;
+        }
+        Ok((hold, amount, freed))
+    }
+
+    // hold_disputed holds back the amount of a disputed transaction on the account and returns how much was held back
+    fn hold_disputed(&self, account: &mut Account, amount: u128) -> Result<u128> {
+        // by default we can only hold back as much money as there is in the account
+        let mut amount = signed(amount)?;
+        if amount > account.available {
+            match self.policy.dispute_hold {
+                DisputeHold::Clamp => amount = account.available.max(0),
+                DisputeHold::Reject => return Err(Error::InsufficientFunds),
+                // the full amount is held back and the available balance goes negative
+                DisputeHold::Full => {}
             }
         }
-        self.set_account(account).await?;
+        account.hold(HoldReason::Dispute, amount)?;
+        Ok(amount as u128)
+    }
+
+    // commit stores the accounts changed by the transaction, given as pairs of their state before and after it,
+    // together with the updated ledger of their currency
+    async fn commit(
+        &mut self,
+        tx: &Transaction,
+        currency: Currency,
+        ledger: Ledger,
+        changes: Vec<(Account, Account)>,
+    ) -> Result<()> {
+        let mut sum_of_totals = self.sums_of_totals.get(&currency).copied().unwrap_or_default();
+        for (before, after) in &changes {
+            sum_of_totals = sum_of_totals.checked_add(after.total - before.total).ok_or(Error::Overflow)?;
+        }
+        if self.verify_mode == VerifyMode::EachTransaction {
+            Self::verify_transaction(tx, &changes, currency, &ledger, sum_of_totals)?;
+        }
+        for (before, account) in &changes {
+            if account.in_deficit() && !before.in_deficit() {
+                warn!(
+                    client = account.id,
+                    currency = %account.currency,
+                    available = account.available,
+                    total = account.total,
+                    "account in deficit"
+                );
+            }
+            self.set_account(account.clone()).await?;
+        }
+        self.ledgers.insert(currency, ledger);
+        self.sums_of_totals.insert(currency, sum_of_totals);
+        // the observers only learn about the changes once all of them are applied
+        for (before, account) in &changes {
+            self.hooks.observe(tx, before, account);
+        }
         Ok(())
     }
 
-    // get_account returns the account for the given client id.
+    // verify_transaction checks the invariants after the given transaction was applied to the accounts
+    fn verify_transaction(
+        tx: &Transaction,
+        changes: &[(Account, Account)],
+        currency: Currency,
+        ledger: &Ledger,
+        sum_of_totals: i128,
+    ) -> Result<()> {
+        changes
+            .iter()
+            .try_for_each(|(_, account)| verify::check_account(account))
+            .and_then(|_| verify::check_conservation(currency, ledger, sum_of_totals))
+            .map_err(|err| match err {
+                Error::InvariantViolation(reason) => {
+                    Error::InvariantViolation(format!("{:?} tx {}: {}", tx.type_, tx.tx, reason))
+                }
+                err => err,
+            })
+    }
+
+    // get_account returns the account of the given client in the given currency.
     // If the account does not exist, it is created and returned.
-    async fn get_account(&mut self, client: ClientID) -> Result<Account> {
+    async fn get_account(&mut self, client: ClientID, currency: Currency) -> Result<Account> {
         let mut accounts = self.accounts.lock().await;
-        match accounts.get(client) {
+        match accounts.get((client, currency)) {
             Ok(account) => Ok(account.clone()),
             Err(_) => {
-                let account = Account::new(client);
-                accounts.set(client, account)?;
-                Ok(accounts.get(client)?.clone())
+                let account = Account {
+                    currency,
+                    ..Account::new(client)
+                };
+                accounts.set(account.key(), account.clone())?;
+                Ok(account)
             }
         }
     }
 
-    // set_account sets the given account for the given client id to the new value.
+    // set_account sets the given account for its client id and currency to the new value.
     async fn set_account(&mut self, account: Account) -> Result<()> {
-        self.accounts.lock().await.set(account.id, account)
+        self.accounts.lock().await.set(account.key(), account)
     }
 }
 
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
+    use super::*;
+    use crate::limits::Limit;
+    use crate::storage::InMemoryKVStore;
+    // the tests spell out their error type, so they use the standard result rather than the one of the crate
+    use std::result::Result;
+
+    type TestManager = Manager<InMemoryKVStore<AccountKey, Account>, InMemoryKVStore<TransactionID, Transaction>>;
+
+    // manager creates a manager on empty in-memory stores that verifies each transaction
+    fn manager() -> Result<TestManager, Error> {
+        Ok(Manager::new(
+            Arc::new(Mutex::new(InMemoryKVStore::new()?)),
+            Arc::new(Mutex::new(InMemoryKVStore::new()?)),
+        )
+        .with_verify_mode(VerifyMode::EachTransaction))
+    }
+
+    // tx creates a transaction in the default currency
+    fn tx(tx: TransactionID, client: ClientID, type_: TxType, amount: Option<u128>) -> Transaction {
+        Transaction { tx, client, type_, amount, ..Default::default() }
+    }
+
+    // account returns the account of the client in the default currency
+    async fn account(mgr: &TestManager, client: ClientID) -> Result<Account, Error> {
+        Ok(mgr.accounts.lock().await.get((client, Currency::DEFAULT))?.clone())
+    }
 
     #[tokio::test]
     async fn test_process_transaction_basic() -> Result<(), crate::error::Error> {
         use super::*;
         use crate::storage::InMemoryKVStore;
     
-        let account_store = Arc::new(Mutex::new(InMemoryKVStore::<ClientID, Account>::new()?));
+        let account_store = Arc::new(Mutex::new(InMemoryKVStore::<AccountKey, Account>::new()?));
         let tx_store = Arc::new(Mutex::new(
             InMemoryKVStore::<TransactionID, Transaction>::new()?,
         ));
@@ -166,13 +1143,14 @@ mod tests {
                 client: 1,
                 type_: TxType::Deposit,
                 amount: Some(100),
+                ..Default::default()
             };
             tx_store.lock().await.set(1, tx.clone())?;
 
             mgr.process_transaction(tx).await?;
 
             let store = account_store.lock().await;
-            let account = store.get(1)?;
+            let account = store.get((1, Currency::DEFAULT))?;
             assert_eq!(account.available, 100);
             assert_eq!(account.total, 100);
             assert_eq!(account.held, 0);
@@ -186,13 +1164,14 @@ mod tests {
                 client: 1,
                 type_: TxType::Withdrawal,
                 amount: Some(50),
+                ..Default::default()
             };
             tx_store.lock().await.set(2, tx.clone())?;
 
             mgr.process_transaction(tx).await?;
 
             let store = account_store.lock().await;
-            let account = store.get(1)?;
+            let account = store.get((1, Currency::DEFAULT))?;
             assert_eq!(account.available, 50);
             assert_eq!(account.total, 50);
             assert_eq!(account.held, 0);
@@ -206,12 +1185,13 @@ mod tests {
                 client: 1,
                 type_: TxType::Dispute,
                 amount: None,
+                ..Default::default()
             };
 
             mgr.process_transaction(tx).await?;
 
             let store = account_store.lock().await;
-            let account = store.get(1)?;
+            let account = store.get((1, Currency::DEFAULT))?;
             assert_eq!(account.available, 0);
             assert_eq!(account.total, 50);
             assert_eq!(account.held, 50);
@@ -225,12 +1205,13 @@ mod tests {
                 client: 1,
                 type_: TxType::Resolve,
                 amount: None,
+                ..Default::default()
             };
 
             mgr.process_transaction(tx).await?;
 
             let store = account_store.lock().await;
-            let account = store.get(1)?;
+            let account = store.get((1, Currency::DEFAULT))?;
             assert_eq!(account.available, 50);
             assert_eq!(account.total, 50);
             assert_eq!(account.held, 0);
@@ -244,12 +1225,13 @@ mod tests {
                 client: 1,
                 type_: TxType::Dispute,
                 amount: None,
+                ..Default::default()
             };
 
             mgr.process_transaction(tx).await?;
 
             let store = account_store.lock().await;
-            let account = store.get(1)?;
+            let account = store.get((1, Currency::DEFAULT))?;
             assert_eq!(account.available, 0);
             assert_eq!(account.total, 50);
             assert_eq!(account.held, 50);
@@ -263,12 +1245,13 @@ mod tests {
                 client: 1,
                 type_: TxType::Chargeback,
                 amount: None,
+                ..Default::default()
             };
 
             mgr.process_transaction(tx).await?;
 
             let store = account_store.lock().await;
-            let account = store.get(1)?;
+            let account = store.get((1, Currency::DEFAULT))?;
             assert_eq!(account.available, 0);
             assert_eq!(account.total, 0);
             assert_eq!(account.held, 0);
@@ -283,7 +1266,7 @@ mod tests {
         use super::*;
         use crate::storage::InMemoryKVStore;
 
-        let account_store = Arc::new(Mutex::new(InMemoryKVStore::<ClientID, Account>::new()?));
+        let account_store = Arc::new(Mutex::new(InMemoryKVStore::<AccountKey, Account>::new()?));
 
         let tx_store = Arc::new(Mutex::new(
             InMemoryKVStore::<TransactionID, Transaction>::new()?,
@@ -292,13 +1275,14 @@ mod tests {
         let mut mgr = Manager::new(account_store.clone(), tx_store.clone());
 
         account_store.lock().await.set(
-            1,
+            (1, Currency::DEFAULT),
             Account {
                 id: 1,
                 available: 100,
                 total: 100,
                 held: 0,
                 locked: false,
+                ..Default::default()
             },
         )?;
 
@@ -309,6 +1293,7 @@ mod tests {
                 client: 1,
                 type_: TxType::Withdrawal,
                 amount: Some(200),
+                ..Default::default()
             };
             tx_store.lock().await.set(2, tx.clone())?;
 
@@ -316,7 +1301,7 @@ mod tests {
             assert!(res.is_err());
 
             let store = account_store.lock().await;
-            let account = store.get(1)?;
+            let account = store.get((1, Currency::DEFAULT))?;
             assert_eq!(account.available, 100);
             assert_eq!(account.total, 100);
             assert_eq!(account.held, 0);
@@ -331,7 +1316,7 @@ mod tests {
         use super::*;
         use crate::storage::InMemoryKVStore;
 
-        let account_store = Arc::new(Mutex::new(InMemoryKVStore::<ClientID, Account>::new()?));
+        let account_store = Arc::new(Mutex::new(InMemoryKVStore::<AccountKey, Account>::new()?));
 
         let tx_store = Arc::new(Mutex::new(
             InMemoryKVStore::<TransactionID, Transaction>::new()?,
@@ -340,13 +1325,14 @@ mod tests {
         let mut mgr = Manager::new(account_store.clone(), tx_store.clone());
 
         account_store.lock().await.set(
-            1,
+            (1, Currency::DEFAULT),
             Account {
                 id: 1,
                 available: 100,
                 total: 100,
                 held: 0,
                 locked: true,
+                ..Default::default()
             },
         )?;
 
@@ -357,6 +1343,7 @@ mod tests {
                 client: 1,
                 type_: TxType::Withdrawal,
                 amount: Some(100),
+                ..Default::default()
             };
             tx_store.lock().await.set(2, tx.clone())?;
 
@@ -364,7 +1351,7 @@ mod tests {
             assert!(res.is_err());
 
             let store = account_store.lock().await;
-            let account = store.get(1)?;
+            let account = store.get((1, Currency::DEFAULT))?;
             assert_eq!(account.available, 100);
             assert_eq!(account.total, 100);
             assert_eq!(account.held, 0);
@@ -380,7 +1367,7 @@ mod tests {
         use super::*;
         use crate::storage::InMemoryKVStore;
 
-        let account_store = Arc::new(Mutex::new(InMemoryKVStore::<ClientID, Account>::new()?));
+        let account_store = Arc::new(Mutex::new(InMemoryKVStore::<AccountKey, Account>::new()?));
 
         let tx_store = Arc::new(Mutex::new(
             InMemoryKVStore::<TransactionID, Transaction>::new()?,
@@ -389,13 +1376,14 @@ mod tests {
         let mut mgr = Manager::new(account_store.clone(), tx_store.clone());
 
         account_store.lock().await.set(
-            1,
+            (1, Currency::DEFAULT),
             Account {
                 id: 1,
                 available: 50,
                 total: 50,
                 held: 0,
                 locked: false,
+                ..Default::default()
             },
         )?;
 
@@ -406,6 +1394,7 @@ mod tests {
                 client: 1,
                 type_: TxType::Deposit,
                 amount: Some(100),
+                ..Default::default()
             },
         )?;
 
@@ -416,12 +1405,13 @@ mod tests {
                 client: 1,
                 type_: TxType::Dispute,
                 amount: None,
+                ..Default::default()
             };
 
             mgr.process_transaction(tx).await?;
 
             let store = account_store.lock().await;
-            let account = store.get(1)?;
+            let account = store.get((1, Currency::DEFAULT))?;
             assert_eq!(account.available, 0);
             assert_eq!(account.total, 50);
             assert_eq!(account.held, 50);
@@ -437,7 +1427,7 @@ mod tests {
         use super::*;
         use crate::storage::InMemoryKVStore;
 
-        let account_store = Arc::new(Mutex::new(InMemoryKVStore::<ClientID, Account>::new()?));
+        let account_store = Arc::new(Mutex::new(InMemoryKVStore::<AccountKey, Account>::new()?));
 
         let tx_store = Arc::new(Mutex::new(
             InMemoryKVStore::<TransactionID, Transaction>::new()?,
@@ -446,13 +1436,14 @@ mod tests {
         let mut mgr = Manager::new(account_store.clone(), tx_store.clone());
 
         account_store.lock().await.set(
-            1,
+            (1, Currency::DEFAULT),
             Account {
                 id: 1,
                 available: 50,
                 total: 50,
                 held: 0,
                 locked: false,
+                ..Default::default()
             },
         )?;
 
@@ -463,6 +1454,7 @@ mod tests {
                 client: 1,
                 type_: TxType::Withdrawal,
                 amount: Some(100),
+                ..Default::default()
             },
         )?;
 
@@ -473,12 +1465,13 @@ mod tests {
                 client: 1,
                 type_: TxType::Dispute,
                 amount: None,
+                ..Default::default()
             };
 
             mgr.process_transaction(tx).await?;
 
             let store = account_store.lock().await;
-            let account = store.get(1)?;
+            let account = store.get((1, Currency::DEFAULT))?;
             assert_eq!(account.available, 50);
             assert_eq!(account.total, 50);
             assert_eq!(account.held, 0);
@@ -487,4 +1480,1064 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_process_transaction_verify_each_transaction() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?;
+
+        // a consistent sequence passes the checks and is tracked in the ledger
+        for tx in [
+            tx(1, 1, TxType::Deposit, Some(100)),
+            tx(2, 1, TxType::Withdrawal, Some(30)),
+            tx(1, 1, TxType::Dispute, None),
+            tx(1, 1, TxType::Chargeback, None),
+        ] {
+            mgr.process_transaction(tx).await?;
+        }
+        assert_eq!(
+            &mgr.ledgers()[&Currency::DEFAULT],
+            &Ledger {
+                deposited: 100,
+                withdrawn: 30,
+                charged_back: 70,
+                reversed: 0,
+                ..Default::default()
+            }
+        );
+
+        // an account whose balances don't add up is reported with the offending transaction
+        mgr.accounts.lock().await.set(
+            (2, Currency::DEFAULT),
+            Account {
+                id: 2,
+                available: 100,
+                total: 50,
+                held: 0,
+                locked: false,
+                ..Default::default()
+            },
+        )?;
+        let res = mgr.process_transaction(tx(3, 2, TxType::Deposit, Some(10))).await;
+        match res {
+            Err(Error::InvariantViolation(reason)) => assert!(reason.contains("tx 3")),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_rejects_overflowing_deposit() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?;
+
+        // amounts that don't fit into a balance at all are rejected as well
+        let res = mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(u128::MAX))).await;
+        assert!(matches!(res, Err(Error::Overflow)));
+
+        mgr.process_transaction(tx(2, 1, TxType::Deposit, Some(i128::MAX as u128))).await?;
+        let res = mgr.process_transaction(tx(3, 1, TxType::Deposit, Some(1))).await;
+        assert!(matches!(res, Err(Error::Overflow)));
+        // neither can the money in the system as a whole
+        let res = mgr.process_transaction(tx(4, 2, TxType::Deposit, Some(1))).await;
+        assert!(matches!(res, Err(Error::Overflow)));
+
+        let account = account(&mgr, 1).await?;
+        assert_eq!(account.available, i128::MAX);
+        assert_eq!(account.total, i128::MAX);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_rejects_dispute_it_cant_hold_back() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?.with_policy(Policy { dispute_hold: DisputeHold::Reject, ..Default::default() });
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100))).await?;
+        mgr.process_transaction(tx(2, 1, TxType::Withdrawal, Some(30))).await?;
+
+        // the deposit was partly withdrawn, so it can't be held back completely
+        let res = mgr.process_transaction(tx(1, 1, TxType::Dispute, None)).await;
+        assert!(matches!(res, Err(Error::InsufficientFunds)));
+        assert_eq!(account(&mgr, 1).await?.held, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_rejects_withdrawal_dispute() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?.with_policy(Policy { withdrawal_disputes: WithdrawalDisputes::Reject, ..Default::default() });
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100))).await?;
+        mgr.process_transaction(tx(2, 1, TxType::Withdrawal, Some(30))).await?;
+
+        let res = mgr.process_transaction(tx(2, 1, TxType::Dispute, None)).await;
+        assert!(matches!(res, Err(Error::NotPermitted)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_ignored_withdrawal_chargeback_locks_on_any_chargeback() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?.with_policy(Policy {
+            withdrawal_chargebacks: WithdrawalChargebacks::Ignore,
+            lock_on: LockOn::AnyChargeback,
+            ..Default::default()
+        });
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100))).await?;
+        mgr.process_transaction(tx(2, 1, TxType::Withdrawal, Some(30))).await?;
+
+        // the chargeback on the withdrawal doesn't credit anything, but still locks the account
+        mgr.process_transaction(tx(2, 1, TxType::Chargeback, None)).await?;
+        let account = account(&mgr, 1).await?;
+        assert_eq!(account.available, 70);
+        assert_eq!(account.total, 70);
+        assert!(account.locked);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_chargeback_doesnt_lock_if_lock_on_never() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?.with_policy(Policy { lock_on: LockOn::Never, ..Default::default() });
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100))).await?;
+        mgr.process_transaction(tx(1, 1, TxType::Dispute, None)).await?;
+        mgr.process_transaction(tx(1, 1, TxType::Chargeback, None)).await?;
+
+        let account = account(&mgr, 1).await?;
+        assert_eq!(account.total, 0);
+        assert!(!account.locked);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_full_dispute_hold_goes_into_deficit() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?.with_policy(Policy { dispute_hold: DisputeHold::Full, ..Default::default() });
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100))).await?;
+        mgr.process_transaction(tx(2, 1, TxType::Withdrawal, Some(70))).await?;
+
+        // the whole deposit is held back, although most of it was already withdrawn
+        mgr.process_transaction(tx(1, 1, TxType::Dispute, None)).await?;
+        let account = account(&mgr, 1).await?;
+        assert_eq!(account.available, -70);
+        assert_eq!(account.held, 100);
+        assert_eq!(account.total, 30);
+        assert!(account.in_deficit());
+
+        // nothing can be withdrawn from an account in deficit
+        let res = mgr.process_transaction(tx(3, 1, TxType::Withdrawal, Some(1))).await;
+        assert!(matches!(res, Err(Error::InsufficientFunds)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_chargeback_in_deficit_takes_full_amount() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?.with_policy(Policy { dispute_hold: DisputeHold::Full, ..Default::default() });
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100))).await?;
+        mgr.process_transaction(tx(2, 1, TxType::Withdrawal, Some(70))).await?;
+        mgr.process_transaction(tx(1, 1, TxType::Dispute, None)).await?;
+
+        // the chargeback takes the full amount, the client owes the rest
+        mgr.process_transaction(tx(1, 1, TxType::Chargeback, None)).await?;
+        let account = account(&mgr, 1).await?;
+        assert_eq!(account.available, -70);
+        assert_eq!(account.held, 0);
+        assert_eq!(account.total, -70);
+        assert!(account.in_deficit());
+        assert!(account.locked);
+        assert_eq!(mgr.ledgers()[&Currency::DEFAULT].charged_back, 100);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_frozen_account_only_receives() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?;
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100))).await?;
+
+        // a frozen account can still receive money, but nothing can be withdrawn
+        mgr.process_transaction(tx(0, 1, TxType::Freeze, None)).await?;
+        mgr.process_transaction(tx(2, 1, TxType::Deposit, Some(10))).await?;
+        let res = mgr.process_transaction(tx(3, 1, TxType::Withdrawal, Some(10))).await;
+        assert!(matches!(res, Err(Error::AccountFrozen)));
+        mgr.process_transaction(tx(0, 1, TxType::Unfreeze, None)).await?;
+        mgr.process_transaction(tx(4, 1, TxType::Withdrawal, Some(10))).await?;
+        assert_eq!(account(&mgr, 1).await?.total, 100);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_unlock_lifts_chargeback_lock() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?;
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100))).await?;
+        mgr.process_transaction(tx(1, 1, TxType::Dispute, None)).await?;
+        mgr.process_transaction(tx(1, 1, TxType::Chargeback, None)).await?;
+        assert!(account(&mgr, 1).await?.locked);
+
+        mgr.process_transaction(tx(0, 1, TxType::Unlock, None)).await?;
+        assert!(!account(&mgr, 1).await?.locked);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_closes_empty_account() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?;
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(50))).await?;
+
+        // an account can only be closed once it's empty, afterwards it rejects everything
+        let res = mgr.process_transaction(tx(0, 1, TxType::Close, None)).await;
+        assert!(matches!(res, Err(Error::AccountNotEmpty)));
+        mgr.process_transaction(tx(2, 1, TxType::Withdrawal, Some(50))).await?;
+        mgr.process_transaction(tx(0, 1, TxType::Close, None)).await?;
+        let res = mgr.process_transaction(tx(3, 1, TxType::Deposit, Some(10))).await;
+        assert!(matches!(res, Err(Error::AccountClosed)));
+        let res = mgr.process_transaction(tx(0, 1, TxType::Unfreeze, None)).await;
+        assert!(matches!(res, Err(Error::AccountClosed)));
+
+        let account = account(&mgr, 1).await?;
+        assert_eq!(account.total, 0);
+        assert!(account.closed);
+        assert!(!account.frozen);
+
+        Ok(())
+    }
+
+    // locked_with_dispute_in_flight returns a manager whose client 1 was locked by a chargeback while tx 2 was disputed
+    async fn locked_with_dispute_in_flight() -> Result<TestManager, Error> {
+        use crate::policy::LockedAccepts;
+
+        let mut mgr = manager()?.with_policy(Policy {
+            locked_accepts: LockedAccepts { deposit: true, resolve: true, chargeback: true, ..Default::default() },
+            ..Default::default()
+        });
+        for tx in [
+            tx(1, 1, TxType::Deposit, Some(100)),
+            tx(2, 1, TxType::Deposit, Some(50)),
+            tx(3, 1, TxType::Deposit, Some(20)),
+            tx(1, 1, TxType::Dispute, None),
+            tx(2, 1, TxType::Dispute, None),
+            tx(1, 1, TxType::Chargeback, None),
+        ] {
+            mgr.process_transaction(tx).await?;
+        }
+        assert!(account(&mgr, 1).await?.locked);
+        Ok(mgr)
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_locked_account_accepts_what_policy_allows() -> Result<(), crate::error::Error> {
+        let mut mgr = locked_with_dispute_in_flight().await?;
+
+        // the dispute that was in flight when the account got locked can still be finished
+        mgr.process_transaction(tx(2, 1, TxType::Resolve, None)).await?;
+        mgr.process_transaction(tx(4, 1, TxType::Deposit, Some(5))).await?;
+
+        let account = account(&mgr, 1).await?;
+        assert_eq!(account.available, 75);
+        assert_eq!(account.held, 0);
+        assert!(account.locked);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_locked_account_rejects_the_rest() -> Result<(), crate::error::Error> {
+        let mut mgr = locked_with_dispute_in_flight().await?;
+
+        // nothing leaves the account and no new disputes are opened
+        let res = mgr.process_transaction(tx(5, 1, TxType::Withdrawal, Some(5))).await;
+        assert!(matches!(res, Err(Error::AccountLocked)));
+        let res = mgr.process_transaction(tx(3, 1, TxType::Dispute, None)).await;
+        assert!(matches!(res, Err(Error::AccountLocked)));
+        assert_eq!(account(&mgr, 1).await?.total, 70);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_multiple_currencies() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?;
+        let usd = Currency::new("usd")?;
+
+        for tx in [
+            tx(1, 1, TxType::Deposit, Some(100)),
+            Transaction { currency: usd, ..tx(2, 1, TxType::Deposit, Some(50)) },
+            Transaction { currency: usd, ..tx(3, 1, TxType::Withdrawal, Some(20)) },
+            // the dispute applies to the currency of the disputed deposit, whatever the row says
+            Transaction { currency: usd, ..tx(1, 1, TxType::Dispute, None) },
+            tx(1, 1, TxType::Chargeback, None),
+        ] {
+            mgr.process_transaction(tx).await?;
+        }
+
+        // balances don't mix between currencies
+        let res = mgr.process_transaction(Transaction { currency: usd, ..tx(4, 1, TxType::Withdrawal, Some(40)) }).await;
+        assert!(matches!(res, Err(Error::InsufficientFunds)));
+
+        let eur = account(&mgr, 1).await?;
+        assert_eq!(eur.total, 0);
+        assert!(eur.locked);
+        let dollars = mgr.accounts.lock().await.get((1, usd))?.clone();
+        assert_eq!(dollars.available, 30);
+        assert_eq!(dollars.total, 30);
+        // the lock only applies to the account in the charged back currency
+        assert!(!dollars.locked);
+
+        assert_eq!(mgr.ledgers()[&Currency::DEFAULT].charged_back, 100);
+        assert_eq!(mgr.ledgers()[&usd].deposited, 50);
+        assert_eq!(mgr.ledgers()[&usd].withdrawn, 20);
+
+        Ok(())
+    }
+
+    // transfer creates a transfer from the client to the destination
+    fn transfer(tx: TransactionID, client: ClientID, destination: ClientID, amount: u128) -> Transaction {
+        Transaction { destination: Some(destination), ..self::tx(tx, client, TxType::Transfer, Some(amount)) }
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_transfers() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?;
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100))).await?;
+        mgr.process_transaction(transfer(2, 1, 2, 60)).await?;
+
+        assert_eq!(account(&mgr, 1).await?.total, 40);
+        assert_eq!(account(&mgr, 2).await?.available, 60);
+        assert_eq!(mgr.ledgers()[&Currency::DEFAULT].transferred, 60);
+        assert_eq!(mgr.ledgers()[&Currency::DEFAULT].balance(), 100);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_rejects_invalid_transfers() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?;
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100))).await?;
+
+        let res = mgr.process_transaction(transfer(2, 1, 2, 110)).await;
+        assert!(matches!(res, Err(Error::InsufficientFunds)));
+        let res = mgr.process_transaction(transfer(3, 1, 1, 10)).await;
+        assert!(matches!(res, Err(Error::InvalidTransfer)));
+        let res = mgr.process_transaction(tx(4, 1, TxType::Transfer, Some(10))).await;
+        assert!(matches!(res, Err(Error::InvalidTransfer)));
+
+        // neither side of a transfer may be locked
+        mgr.process_transaction(tx(5, 3, TxType::Deposit, Some(10))).await?;
+        mgr.process_transaction(tx(5, 3, TxType::Dispute, None)).await?;
+        mgr.process_transaction(tx(5, 3, TxType::Chargeback, None)).await?;
+        let res = mgr.process_transaction(transfer(6, 1, 3, 10)).await;
+        assert!(matches!(res, Err(Error::AccountLocked)));
+        assert_eq!(account(&mgr, 1).await?.available, 100);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_only_source_disputes_transfer() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?;
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100))).await?;
+        mgr.process_transaction(transfer(2, 1, 2, 60)).await?;
+
+        for client in [2, 7] {
+            for type_ in [TxType::Dispute, TxType::Chargeback] {
+                let res = mgr.process_transaction(tx(2, client, type_, None)).await;
+                assert!(matches!(res, Err(Error::NotFound)));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_transfer_chargeback_returns_money() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?;
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100))).await?;
+        mgr.process_transaction(transfer(2, 1, 2, 60)).await?;
+
+        // the destination holds the disputed amount back and a chargeback returns it to the source
+        mgr.process_transaction(tx(2, 1, TxType::Dispute, None)).await?;
+        let destination = account(&mgr, 2).await?;
+        assert_eq!(destination.available, 0);
+        assert_eq!(destination.held, 60);
+        mgr.process_transaction(tx(2, 1, TxType::Chargeback, None)).await?;
+
+        let source = account(&mgr, 1).await?;
+        assert_eq!(source.available, 100);
+        assert_eq!(source.total, 100);
+        assert!(!source.locked);
+        let destination = account(&mgr, 2).await?;
+        assert_eq!(destination.held, 0);
+        assert_eq!(destination.total, 0);
+        assert_eq!(mgr.ledgers()[&Currency::DEFAULT].balance(), 100);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_rejected_transfer_cant_be_charged_back() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?;
+        mgr.process_transaction(tx(1, 2, TxType::Deposit, Some(10))).await?;
+        let res = mgr.process_transaction(transfer(5, 1, 2, 100)).await;
+        assert!(matches!(res, Err(Error::InsufficientFunds)));
+
+        // the rejected transfer moved no money, so there is nothing to dispute or charge back
+        let res = mgr.process_transaction(tx(5, 1, TxType::Dispute, None)).await;
+        assert!(matches!(res, Err(Error::NotFound)));
+        let res = mgr.process_transaction(tx(5, 1, TxType::Chargeback, None)).await;
+        assert!(matches!(res, Err(Error::NotFound)));
+        assert_eq!(account(&mgr, 1).await?.total, 0);
+        assert_eq!(account(&mgr, 2).await?.available, 10);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_charges_fees() -> Result<(), crate::error::Error> {
+        let fees: FeeSchedule = "house = 9\nrefund_on_chargeback = true\ndeposit = 1%\nwithdrawal = 0.01\n".parse()?;
+        let mut mgr = manager()?
+            .with_policy(Policy { lock_on: LockOn::Never, ..Default::default() })
+            .with_fees(fees);
+        let balances = |account: Account| (account.available, account.held, account.total);
+
+        // 1% of the deposit goes to the house account
+        let applied = mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100_000))).await?;
+        assert_eq!(applied, Applied { currency: Currency::DEFAULT, fee: 1000, ..Default::default() });
+        assert_eq!(mgr.transactions.lock().await.get(1)?.fee, 1000);
+
+        // the fee of a withdrawal is paid on top of it
+        assert_eq!(mgr.process_transaction(tx(2, 1, TxType::Withdrawal, Some(50_000))).await?.fee, 100);
+        let res = mgr.process_transaction(tx(3, 1, TxType::Withdrawal, Some(48_900))).await;
+        assert!(matches!(res, Err(Error::InsufficientFunds)));
+
+        // the house account doesn't pay fees to itself
+        assert_eq!(mgr.process_transaction(tx(4, 9, TxType::Deposit, Some(10_000))).await?.fee, 0);
+        assert_eq!(balances(account(&mgr, 1).await?), (48_900, 0, 48_900));
+        assert_eq!(balances(account(&mgr, 9).await?), (11_100, 0, 11_100));
+
+        // the chargeback takes what is left of the deposit and refunds the fee of that part
+        mgr.process_transaction(tx(1, 1, TxType::Dispute, None)).await?;
+        let applied = mgr.process_transaction(tx(1, 1, TxType::Chargeback, None)).await?;
+        assert_eq!(applied.refunded_fee, 489);
+        assert_eq!(balances(account(&mgr, 1).await?), (489, 0, 489));
+        assert_eq!(balances(account(&mgr, 9).await?), (10_611, 0, 10_611));
+        assert_eq!(mgr.ledgers()[&Currency::DEFAULT].fees, 611);
+        assert_eq!(mgr.chargebacks().collect::<Vec<_>>(), vec![ChargedBack { tx: 1, amount: 48_900 }]);
+
+        // the fee of a part is refunded once, a chargeback that takes nothing more refunds nothing
+        let applied = mgr.process_transaction(tx(1, 1, TxType::Chargeback, None)).await?;
+        assert_eq!(applied.refunded_fee, 0);
+        assert_eq!(balances(account(&mgr, 1).await?), (489, 0, 489));
+        assert_eq!(balances(account(&mgr, 9).await?), (10_611, 0, 10_611));
+
+        // a chargeback of the rest refunds the rest of the fee
+        mgr.process_transaction(tx(5, 1, TxType::Deposit, Some(60_000))).await?;
+        mgr.process_transaction(tx(1, 1, TxType::Dispute, None)).await?;
+        let applied = mgr.process_transaction(tx(1, 1, TxType::Chargeback, None)).await?;
+        assert_eq!(applied.refunded_fee, 511);
+        assert_eq!(mgr.transactions.lock().await.get(1)?.charged_back, 100_000);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_withdrawal_chargebacks_refund_fee_once() -> Result<(), crate::error::Error> {
+        let fees: FeeSchedule = "house = 9\nrefund_on_chargeback = true\nwithdrawal = 1%\n".parse()?;
+        let mut mgr = manager()?
+            .with_policy(Policy {
+                dispute_amounts: DisputeAmounts::Partial,
+                lock_on: LockOn::Never,
+                ..Default::default()
+            })
+            .with_fees(fees);
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100_000))).await?;
+        assert_eq!(mgr.process_transaction(tx(2, 1, TxType::Withdrawal, Some(50_000))).await?.fee, 500);
+
+        // every chargeback refunds the fee of the part it adds to the ones before
+        let applied = mgr.process_transaction(tx(2, 1, TxType::Chargeback, Some(20_000))).await?;
+        assert_eq!(applied.refunded_fee, 200);
+        let applied = mgr.process_transaction(tx(2, 1, TxType::Chargeback, Some(30_000))).await?;
+        assert_eq!(applied.refunded_fee, 300);
+        assert_eq!(mgr.transactions.lock().await.get(2)?.charged_back, 50_000);
+
+        // once the whole withdrawal was charged back, its fee is refunded completely
+        let applied = mgr.process_transaction(tx(2, 1, TxType::Chargeback, Some(10_000))).await?;
+        assert_eq!(applied.refunded_fee, 0);
+        assert_eq!(account(&mgr, 9).await?.total, 0);
+        assert_eq!(mgr.ledgers()[&Currency::DEFAULT].fees, 0);
+
+        Ok(())
+    }
+
+    // partly_disputed returns a manager that tracks partial disputes, where client 1 withdrew most of the deposit tx 1
+    // and disputed 50_000 of it
+    async fn partly_disputed() -> Result<TestManager, Error> {
+        let mut mgr = manager()?
+            .with_policy(Policy {
+                dispute_tracking: DisputeTracking::Transaction,
+                dispute_amounts: DisputeAmounts::Partial,
+                lock_on: LockOn::Never,
+                ..Default::default()
+            })
+            .with_fees("house = 9\nrefund_on_chargeback = true\ndeposit = 1%\n".parse()?);
+        for tx in [
+            tx(1, 1, TxType::Deposit, Some(100_000)),
+            tx(2, 1, TxType::Withdrawal, Some(69_000)),
+            tx(1, 1, TxType::Dispute, Some(20_000)),
+            tx(1, 1, TxType::Dispute, Some(30_000)),
+        ] {
+            mgr.process_transaction(tx).await?;
+        }
+        Ok(mgr)
+    }
+
+    // dispute returns the held back, disputed and charged back amount of the only open hold
+    fn dispute(mgr: &TestManager) -> Option<(u128, u128, u128)> {
+        mgr.holds().next().map(|hold| (hold.amount, hold.disputed, hold.charged_back))
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_partial_disputes_add_up() -> Result<(), crate::error::Error> {
+        let mut mgr = partly_disputed().await?;
+
+        // the second part of the deposit can only be held back as far as the account covers it
+        let account = account(&mgr, 1).await?;
+        assert_eq!((account.available, account.held, account.total), (0, 30_000, 30_000));
+        assert_eq!(dispute(&mgr), Some((30_000, 50_000, 0)));
+
+        let res = mgr.process_transaction(tx(1, 1, TxType::Dispute, Some(50_001))).await;
+        assert!(matches!(res, Err(Error::ExceedsUndisputed)));
+        let res = mgr.process_transaction(tx(1, 1, TxType::Resolve, Some(50_001))).await;
+        assert!(matches!(res, Err(Error::ExceedsDisputed)));
+        let res = mgr
+            .process_transaction(Transaction { currency: Currency::new("USD")?, ..tx(1, 1, TxType::Resolve, Some(1)) })
+            .await;
+        assert!(matches!(res, Err(Error::InvalidCurrency(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_partial_chargeback_refunds_fee_share() -> Result<(), crate::error::Error> {
+        let mut mgr = partly_disputed().await?;
+
+        // a partial chargeback takes held back money first and refunds its share of the fee
+        let applied = mgr.process_transaction(tx(1, 1, TxType::Chargeback, Some(25_000))).await?;
+        assert_eq!(applied.refunded_fee, 250);
+        let account = account(&mgr, 1).await?;
+        assert_eq!((account.available, account.held, account.total), (250, 5_000, 5_250));
+        mgr.process_transaction(tx(1, 1, TxType::Resolve, None)).await?;
+        assert_eq!(dispute(&mgr), Some((0, 0, 25_000)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_redisputes_what_was_resolved() -> Result<(), crate::error::Error> {
+        let mut mgr = partly_disputed().await?;
+        mgr.process_transaction(tx(1, 1, TxType::Chargeback, Some(25_000))).await?;
+        mgr.process_transaction(tx(1, 1, TxType::Resolve, None)).await?;
+
+        // what was resolved can be disputed again, what was charged back can't
+        mgr.process_transaction(tx(1, 1, TxType::Dispute, None)).await?;
+        assert_eq!(dispute(&mgr), Some((5_250, 75_000, 25_000)));
+        let applied = mgr.process_transaction(tx(1, 1, TxType::Chargeback, None)).await?;
+        assert_eq!(applied.refunded_fee, 750);
+        let account = account(&mgr, 1).await?;
+        assert_eq!((account.available, account.held, account.total), (750, 0, 750));
+        let res = mgr.process_transaction(tx(1, 1, TxType::Dispute, None)).await;
+        assert!(matches!(res, Err(Error::AlreadyDisputed)));
+        assert_eq!(mgr.ledgers()[&Currency::DEFAULT].fees, 0);
+
+        Ok(())
+    }
+
+    // claimed returns a manager that treats withdrawal disputes as claims, where client 1 withdrew 40 of 100
+    async fn claimed() -> Result<TestManager, Error> {
+        let mut mgr = manager()?.with_policy(Policy {
+            withdrawal_disputes: WithdrawalDisputes::Claim,
+            lock_on: LockOn::AnyChargeback,
+            ..Default::default()
+        });
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100))).await?;
+        mgr.process_transaction(tx(2, 1, TxType::Withdrawal, Some(40))).await?;
+        Ok(mgr)
+    }
+
+    // claim returns the available, total and pending credit balance of client 1 and whether it's locked
+    async fn claim(mgr: &TestManager) -> Result<(i128, i128, i128, bool), Error> {
+        let account = account(mgr, 1).await?;
+        Ok((account.available, account.total, account.pending_credit, account.locked))
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_withdrawal_dispute_opens_claim() -> Result<(), crate::error::Error> {
+        let mut mgr = claimed().await?;
+
+        // only a claimed withdrawal can be charged back
+        let res = mgr.process_transaction(tx(2, 1, TxType::Chargeback, None)).await;
+        assert!(matches!(res, Err(Error::NotDisputed)));
+        mgr.process_transaction(tx(2, 1, TxType::Dispute, None)).await?;
+        assert_eq!(claim(&mgr).await?, (60, 60, 40, false));
+        let res = mgr.process_transaction(tx(2, 1, TxType::Dispute, None)).await;
+        assert!(matches!(res, Err(Error::AlreadyDisputed)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_resolve_discards_claim() -> Result<(), crate::error::Error> {
+        let mut mgr = claimed().await?;
+        mgr.process_transaction(tx(2, 1, TxType::Dispute, None)).await?;
+
+        mgr.process_transaction(tx(2, 1, TxType::Resolve, None)).await?;
+        assert_eq!(claim(&mgr).await?, (60, 60, 0, false));
+        assert_eq!(mgr.holds().count(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_chargeback_credits_claim() -> Result<(), crate::error::Error> {
+        let mut mgr = claimed().await?;
+        mgr.process_transaction(tx(2, 1, TxType::Dispute, None)).await?;
+
+        let res = mgr.process_transaction(tx(2, 2, TxType::Chargeback, None)).await;
+        assert!(matches!(res, Err(Error::NotDisputed)));
+        mgr.process_transaction(tx(2, 1, TxType::Chargeback, None)).await?;
+        assert_eq!(claim(&mgr).await?, (100, 100, 0, true));
+        assert_eq!(mgr.ledgers()[&Currency::DEFAULT].reversed, 40);
+
+        Ok(())
+    }
+
+    const START: Timestamp = 1_700_000_000;
+
+    // timed creates a transaction at the given day after the start, or without a timestamp
+    fn timed(tx: TransactionID, client: ClientID, type_: TxType, amount: Option<u128>, days: Option<u64>) -> Transaction {
+        Transaction { timestamp: days.map(|days| START + days * SECONDS_PER_DAY), ..self::tx(tx, client, type_, amount) }
+    }
+
+    // windowed returns a manager with a dispute window of 120 days and a deadline of 30 days, where client 1 deposited
+    // tx 1 at the start and tx 2 without a timestamp
+    async fn windowed() -> Result<TestManager, Error> {
+        let mut mgr = manager()?.with_policy(Policy {
+            dispute_tracking: DisputeTracking::Transaction,
+            dispute_window: 120,
+            dispute_deadline: 30,
+            ..Default::default()
+        });
+        mgr.process_transaction(timed(1, 1, TxType::Deposit, Some(100), Some(0))).await?;
+        mgr.process_transaction(timed(2, 1, TxType::Deposit, Some(50), None)).await?;
+        Ok(mgr)
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_untimed_transaction_takes_latest_time() -> Result<(), crate::error::Error> {
+        let mgr = windowed().await?;
+
+        assert_eq!(mgr.transactions.lock().await.get(2)?.timestamp, Some(START));
+        assert_eq!(mgr.clock(), Some(START));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_resolves_dispute_after_deadline() -> Result<(), crate::error::Error> {
+        let mut mgr = windowed().await?;
+        mgr.process_transaction(timed(2, 1, TxType::Dispute, None, Some(10))).await?;
+        assert_eq!(mgr.holds().next().and_then(|hold| hold.deadline), Some(START + 40 * SECONDS_PER_DAY));
+
+        // the dispute is resolved by the first transaction after its deadline, whoever it's from
+        mgr.process_transaction(timed(3, 1, TxType::Deposit, Some(1), Some(40))).await?;
+        assert_eq!(account(&mgr, 1).await?.held, 50);
+        mgr.process_transaction(timed(4, 2, TxType::Deposit, Some(1), Some(41))).await?;
+        assert_eq!(account(&mgr, 1).await?.held, 0);
+        assert_eq!(mgr.holds().count(), 0);
+        let res = mgr.process_transaction(timed(2, 1, TxType::Resolve, None, Some(41))).await;
+        assert!(matches!(res, Err(Error::NotDisputed)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_rejects_dispute_after_window() -> Result<(), crate::error::Error> {
+        let mut mgr = windowed().await?;
+
+        // also if the dispute has no timestamp itself
+        let res = mgr.process_transaction(timed(1, 1, TxType::Dispute, None, Some(121))).await;
+        assert!(matches!(res, Err(Error::DisputeWindowClosed)));
+        let res = mgr.process_transaction(timed(2, 1, TxType::Dispute, None, None)).await;
+        assert!(matches!(res, Err(Error::DisputeWindowClosed)));
+
+        Ok(())
+    }
+
+    // limited returns a manager with limits for clients of the default tier, where client 1 deposited 1000 at the start
+    async fn limited() -> Result<TestManager, Error> {
+        let limits = "default.single = 50\ndefault.daily = 80\ndefault.weekly_count = 3\nclient 2 = vip\n".parse()?;
+        let mut mgr = manager()?.with_limits(limits);
+        mgr.process_transaction(hourly(1, 1, TxType::Deposit, 1000, 0)).await?;
+        Ok(mgr)
+    }
+
+    // hourly creates a transaction of whole units at the given hour after the start
+    fn hourly(tx: TransactionID, client: ClientID, type_: TxType, amount: u128, hours: u64) -> Transaction {
+        Transaction { timestamp: Some(START + hours * 3600), ..self::tx(tx, client, type_, Some(amount * 10_000)) }
+    }
+
+    // exceeded returns the limit the transaction exceeded, if any
+    fn exceeded<T>(res: Result<T, Error>) -> Option<Limit> {
+        match res {
+            Err(Error::LimitExceeded(limit)) => Some(limit),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_single_withdrawal_limit() -> Result<(), crate::error::Error> {
+        let mut mgr = limited().await?;
+
+        let res = mgr.process_transaction(hourly(2, 1, TxType::Withdrawal, 60, 0)).await;
+        assert_eq!(exceeded(res), Some(Limit::Single));
+        mgr.process_transaction(hourly(3, 1, TxType::Withdrawal, 50, 0)).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_daily_withdrawal_limit() -> Result<(), crate::error::Error> {
+        let mut mgr = limited().await?;
+        mgr.process_transaction(hourly(2, 1, TxType::Withdrawal, 50, 1)).await?;
+
+        let res = mgr.process_transaction(hourly(3, 1, TxType::Withdrawal, 40, 2)).await;
+        assert_eq!(exceeded(res), Some(Limit::Daily));
+        // rejected withdrawals don't count, and after 24 hours the first one doesn't count against the daily limit
+        mgr.process_transaction(hourly(4, 1, TxType::Withdrawal, 30, 2)).await?;
+        mgr.process_transaction(hourly(5, 1, TxType::Withdrawal, 50, 25)).await?;
+        assert_eq!(account(&mgr, 1).await?.available, 8_700_000);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_weekly_withdrawal_count() -> Result<(), crate::error::Error> {
+        let mut mgr = limited().await?;
+        for (tx, hours) in [(2, 0), (3, 30), (4, 60)] {
+            mgr.process_transaction(hourly(tx, 1, TxType::Withdrawal, 1, hours)).await?;
+        }
+
+        let res = mgr.process_transaction(hourly(5, 1, TxType::Withdrawal, 1, 100)).await;
+        assert_eq!(exceeded(res), Some(Limit::WeeklyCount));
+        mgr.process_transaction(hourly(6, 1, TxType::Withdrawal, 1, 7 * 24 + 1)).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_tier_without_limits() -> Result<(), crate::error::Error> {
+        let mut mgr = limited().await?;
+
+        mgr.process_transaction(hourly(2, 2, TxType::Deposit, 1000, 0)).await?;
+        mgr.process_transaction(hourly(3, 2, TxType::Withdrawal, 1000, 0)).await?;
+        assert_eq!(account(&mgr, 2).await?.total, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_limits_authorizations_not_transfers() -> Result<(), crate::error::Error> {
+        let mut mgr = limited().await?;
+
+        // an authorization is checked like a withdrawal, but only the captured amount counts against the limits
+        let res = mgr.process_transaction(hourly(2, 1, TxType::Authorize, 60, 0)).await;
+        assert_eq!(exceeded(res), Some(Limit::Single));
+        mgr.process_transaction(hourly(3, 1, TxType::Authorize, 50, 1)).await?;
+        mgr.process_transaction(hourly(3, 1, TxType::Capture, 30, 2)).await?;
+        mgr.process_transaction(hourly(4, 1, TxType::Withdrawal, 50, 3)).await?;
+        let res = mgr.process_transaction(hourly(5, 1, TxType::Withdrawal, 1, 3)).await;
+        assert_eq!(exceeded(res), Some(Limit::Daily));
+
+        // a transfer keeps the money in the system, so it's neither limited nor counted
+        let transfer = Transaction { timestamp: Some(START + 4 * 3600), ..transfer(6, 1, 3, 100 * 10_000) };
+        mgr.process_transaction(transfer).await?;
+        mgr.process_transaction(hourly(7, 1, TxType::Withdrawal, 1, 27)).await?;
+        let res = mgr.process_transaction(hourly(8, 1, TxType::Withdrawal, 1, 28)).await;
+        assert_eq!(exceeded(res), Some(Limit::WeeklyCount));
+
+        Ok(())
+    }
+
+    type Calls = Arc<std::sync::Mutex<Vec<String>>>;
+
+    // hooked returns a manager whose first validator rejects client 2 and whose second one flags withdrawals of more
+    // than half the available balance, with an observer after them, along with the log of their calls
+    fn hooked() -> Result<(TestManager, Calls), Error> {
+        use crate::hooks::Verdict;
+
+        let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (first, second, observed) = (calls.clone(), calls.clone(), calls.clone());
+        let mgr = manager()?
+            .with_fees("house = 9\nwithdrawal = 1".parse()?)
+            .with_validator(move |tx: &Transaction, _: &Account| {
+                first.lock().unwrap().push(format!("first {}", tx.tx));
+                match tx.client {
+                    2 => Verdict::Reject("sanctioned".to_string()),
+                    _ => Verdict::Accept,
+                }
+            })
+            .with_validator(move |tx: &Transaction, account: &Account| {
+                second.lock().unwrap().push(format!("second {}", tx.tx));
+                match tx.amount {
+                    Some(amount) if tx.type_ == TxType::Withdrawal && amount as i128 * 2 > account.available => {
+                        Verdict::Flag("large withdrawal".to_string())
+                    }
+                    _ => Verdict::Accept,
+                }
+            })
+            .with_observer(move |tx: &Transaction, before: &Account, after: &Account| {
+                observed.lock().unwrap().push(format!("observed {} {} {}", tx.tx, after.id, after.total - before.total));
+            });
+        Ok((mgr, calls))
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_calls_hooks_in_order() -> Result<(), crate::error::Error> {
+        let (mut mgr, calls) = hooked()?;
+
+        let applied = mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100_000))).await?;
+        assert!(applied.flags.is_empty());
+        let applied = mgr.process_transaction(tx(2, 1, TxType::Withdrawal, Some(60_000))).await?;
+        assert_eq!(applied.flags, vec!["large withdrawal".to_string()]);
+
+        // the observer sees every account the transaction changed, the house account included
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["first 1", "second 1", "observed 1 1 100000", "first 2", "second 2", "observed 2 1 -70000", "observed 2 9 10000"]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_rejected_transaction_isnt_observed() -> Result<(), crate::error::Error> {
+        let (mut mgr, calls) = hooked()?;
+
+        // the first rejection is final and nothing changes, so there is nothing to observe
+        let res = mgr.process_transaction(tx(1, 2, TxType::Deposit, Some(100_000))).await;
+        assert!(matches!(res, Err(Error::Rejected(reason)) if reason == "sanctioned"));
+        // the business rules come after the validators, and a transaction they reject isn't observed either
+        let res = mgr.process_transaction(tx(2, 1, TxType::Withdrawal, Some(100_000))).await;
+        assert!(matches!(res, Err(Error::InsufficientFunds)));
+
+        assert_eq!(*calls.lock().unwrap(), vec!["first 1", "first 2", "second 2"]);
+
+        Ok(())
+    }
+
+    // reservations returns the available, dispute held, authorization held and total balance of client 1
+    async fn reservations(mgr: &TestManager) -> Result<(i128, i128, i128, i128), Error> {
+        let account = account(mgr, 1).await?;
+        Ok((account.available, account.held_dispute, account.held_authorization, account.total))
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_authorization_reserves_funds() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?;
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100))).await?;
+        mgr.process_transaction(tx(2, 1, TxType::Authorize, Some(60))).await?;
+        assert_eq!(reservations(&mgr).await?, (40, 0, 60, 100));
+
+        let res = mgr.process_transaction(tx(3, 1, TxType::Authorize, Some(50))).await;
+        assert!(matches!(res, Err(Error::InsufficientFunds)));
+        let res = mgr.process_transaction(tx(2, 1, TxType::Capture, Some(70))).await;
+        assert!(matches!(res, Err(Error::ExceedsAuthorization)));
+        let res = mgr.process_transaction(tx(2, 2, TxType::Capture, Some(10))).await;
+        assert!(matches!(res, Err(Error::NotFound)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_partial_capture_releases_rest() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?;
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100))).await?;
+        mgr.process_transaction(tx(2, 1, TxType::Authorize, Some(60))).await?;
+
+        mgr.process_transaction(tx(2, 1, TxType::Capture, Some(25))).await?;
+        assert_eq!(reservations(&mgr).await?, (75, 0, 0, 75));
+        assert_eq!(mgr.ledgers()[&Currency::DEFAULT].withdrawn, 25);
+        let res = mgr.process_transaction(tx(2, 1, TxType::Void, None)).await;
+        assert!(matches!(res, Err(Error::NotFound)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_capture_charges_withdrawal_fee() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?.with_fees("house = 9\nrefund_on_chargeback = true\nwithdrawal = 10%\n".parse()?);
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(100_000))).await?;
+        mgr.process_transaction(tx(2, 1, TxType::Authorize, Some(60_000))).await?;
+
+        // the fee of a withdrawal of the captured amount is paid on top of it
+        let applied = mgr.process_transaction(tx(2, 1, TxType::Capture, Some(50_000))).await?;
+        assert_eq!(applied.fee, 5_000);
+        assert_eq!(reservations(&mgr).await?, (45_000, 0, 0, 45_000));
+        assert_eq!(account(&mgr, 9).await?.total, 5_000);
+        assert_eq!(mgr.ledgers()[&Currency::DEFAULT].fees, 5_000);
+
+        // a capture isn't stored, so it can't be charged back and its fee is never refunded
+        let res = mgr.process_transaction(tx(2, 1, TxType::Chargeback, None)).await;
+        assert!(matches!(res, Err(Error::NotFound)));
+
+        // the capture is rejected if the available balance doesn't cover the fee, the reservation stays open
+        mgr.process_transaction(tx(3, 1, TxType::Authorize, Some(45_000))).await?;
+        let res = mgr.process_transaction(tx(3, 1, TxType::Capture, None)).await;
+        assert!(matches!(res, Err(Error::InsufficientFunds)));
+        assert_eq!(reservations(&mgr).await?, (0, 0, 45_000, 45_000));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_dispute_leaves_reservation() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?;
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(75))).await?;
+        mgr.process_transaction(tx(2, 1, TxType::Authorize, Some(50))).await?;
+
+        // disputes only hold and release what is available, never the reservation
+        mgr.process_transaction(tx(1, 1, TxType::Dispute, None)).await?;
+        assert_eq!(reservations(&mgr).await?, (0, 25, 50, 75));
+        mgr.process_transaction(tx(1, 1, TxType::Resolve, None)).await?;
+        assert_eq!(reservations(&mgr).await?, (25, 0, 50, 75));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_authorization_expires() -> Result<(), crate::error::Error> {
+        let mut mgr = manager()?.with_policy(Policy { authorization_expiry: 3, ..Default::default() });
+        mgr.process_transaction(tx(1, 1, TxType::Deposit, Some(75))).await?;
+        mgr.process_transaction(tx(2, 1, TxType::Authorize, Some(50))).await?;
+
+        // the authorization 2 expires once three more transactions came after it, before the void of 3 is applied
+        mgr.process_transaction(tx(3, 1, TxType::Authorize, Some(10))).await?;
+        mgr.process_transaction(tx(4, 1, TxType::Deposit, Some(1))).await?;
+        mgr.process_transaction(tx(5, 1, TxType::Deposit, Some(1))).await?;
+        mgr.process_transaction(tx(3, 1, TxType::Void, None)).await?;
+        assert_eq!(reservations(&mgr).await?, (77, 0, 0, 77));
+        let res = mgr.process_transaction(tx(2, 1, TxType::Capture, None)).await;
+        assert!(matches!(res, Err(Error::NotFound)));
+        assert_eq!(mgr.holds().count(), 0);
+
+        Ok(())
+    }
+
+    // held_by_reason returns the available, held, dispute held and compliance held balance of client 1
+    async fn held_by_reason(mgr: &TestManager) -> Result<(i128, i128, i128, i128), Error> {
+        let account = account(mgr, 1).await?;
+        Ok((account.available, account.held, account.held_dispute, account.held_compliance))
+    }
+
+    // tracked_with_holds returns a manager that tracks disputes per transaction, with a dispute on tx 1 and a compliance
+    // hold of tx 3 on client 1
+    async fn tracked_with_holds() -> Result<TestManager, Error> {
+        let mut mgr = manager()?.with_policy(Policy { dispute_tracking: DisputeTracking::Transaction, ..Default::default() });
+        for tx in [
+            tx(1, 1, TxType::Deposit, Some(100)),
+            tx(2, 1, TxType::Deposit, Some(50)),
+            tx(3, 1, TxType::Hold, Some(30)),
+            tx(1, 1, TxType::Dispute, None),
+        ] {
+            mgr.process_transaction(tx).await?;
+        }
+        Ok(mgr)
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_holds_by_reason() -> Result<(), crate::error::Error> {
+        let mgr = tracked_with_holds().await?;
+
+        assert_eq!(held_by_reason(&mgr).await?, (20, 130, 100, 30));
+        let mut holds: Vec<(TransactionID, HoldReason)> = mgr.holds().map(|hold| (hold.tx, hold.reason)).collect();
+        holds.sort();
+        assert_eq!(holds, vec![(1, HoldReason::Dispute), (3, HoldReason::Compliance)]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_tracks_disputes_per_transaction() -> Result<(), crate::error::Error> {
+        let mut mgr = tracked_with_holds().await?;
+
+        let res = mgr.process_transaction(tx(1, 1, TxType::Dispute, None)).await;
+        assert!(matches!(res, Err(Error::AlreadyDisputed)));
+        let res = mgr.process_transaction(tx(2, 1, TxType::Resolve, None)).await;
+        assert!(matches!(res, Err(Error::NotDisputed)));
+        let res = mgr.process_transaction(tx(2, 1, TxType::Chargeback, None)).await;
+        assert!(matches!(res, Err(Error::NotDisputed)));
+        assert!(!account(&mgr, 1).await?.locked);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_release_lifts_its_compliance_hold() -> Result<(), crate::error::Error> {
+        let mut mgr = tracked_with_holds().await?;
+
+        let res = mgr.process_transaction(tx(3, 2, TxType::Release, None)).await;
+        assert!(matches!(res, Err(Error::NotFound)));
+        mgr.process_transaction(tx(3, 1, TxType::Release, None)).await?;
+        assert_eq!(held_by_reason(&mgr).await?, (50, 100, 100, 0));
+        mgr.process_transaction(tx(1, 1, TxType::Resolve, None)).await?;
+        assert_eq!(held_by_reason(&mgr).await?, (150, 0, 0, 0));
+        assert_eq!(mgr.holds().count(), 0);
+
+        Ok(())
+    }
+
+    // untracked_with_holds returns a manager that tracks disputes per account, with a dispute on tx 1 and a compliance
+    // hold of tx 3 on client 1
+    async fn untracked_with_holds() -> Result<TestManager, Error> {
+        let mut mgr = manager()?;
+        for tx in [
+            tx(1, 1, TxType::Deposit, Some(100)),
+            tx(2, 1, TxType::Deposit, Some(50)),
+            tx(3, 1, TxType::Hold, Some(30)),
+            tx(1, 1, TxType::Dispute, None),
+        ] {
+            mgr.process_transaction(tx).await?;
+        }
+        assert_eq!(held_by_reason(&mgr).await?, (20, 130, 100, 30));
+        Ok(mgr)
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_account_dispute_holds_what_is_available() -> Result<(), crate::error::Error> {
+        let mut mgr = untracked_with_holds().await?;
+
+        // by default a second dispute holds back what is still available
+        mgr.process_transaction(tx(1, 1, TxType::Dispute, None)).await?;
+        assert_eq!(held_by_reason(&mgr).await?, (0, 150, 120, 30));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_transaction_account_disputes_free_other_disputes() -> Result<(), crate::error::Error> {
+        let mut mgr = untracked_with_holds().await?;
+
+        // a resolve or chargeback of a transaction that isn't disputed frees the money held back for other disputes,
+        // but never the compliance hold
+        mgr.process_transaction(tx(2, 1, TxType::Resolve, None)).await?;
+        assert_eq!(held_by_reason(&mgr).await?, (70, 80, 50, 30));
+        mgr.process_transaction(tx(2, 1, TxType::Chargeback, None)).await?;
+        assert_eq!(held_by_reason(&mgr).await?, (70, 30, 0, 30));
+        assert!(account(&mgr, 1).await?.locked);
+        // only the compliance hold is recorded
+        let holds: Vec<(TransactionID, HoldReason)> = mgr.holds().map(|hold| (hold.tx, hold.reason)).collect();
+        assert_eq!(holds, vec![(3, HoldReason::Compliance)]);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,241 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{
+    error::{Error, Result},
+    types::{Account, AccountRow, Currency, Transaction, TransactionRow},
+};
+
+// Precision is the number of decimal places of an asset
+pub type Precision = u32;
+
+// assets that are not in the registry have the precision the engine always used
+pub const DEFAULT_PRECISION: Precision = 4;
+
+// 18 decimal places is the most that is in common use (e.g. ERC-20 tokens),
+// it still leaves room for amounts of up to 10^20 whole units in the internal representation
+pub const MAX_PRECISION: Precision = 18;
+
+// Assets is the registry of the precision of every currency or asset
+// Amounts are kept as integers of the smallest unit of their asset, e.g. 12.5 JPY is not a valid amount and
+// 1.5 BTC is stored as 150000000 satoshi
+//
+// An assets file has one `CODE = decimal places` pair per line, empty lines and lines starting with `#` are ignored:
+//
+//   JPY = 0
+//   BTC = 8
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Assets {
+    precisions: BTreeMap<Currency, Precision>,
+}
+
+impl Assets {
+    // load reads the registry from the given file
+    pub async fn load(path: &Path) -> Result<Self> {
+        tokio::fs::read_to_string(path).await?.parse()
+    }
+
+    pub fn precision(&self, currency: Currency) -> Precision {
+        self.precisions.get(&currency).copied().unwrap_or(DEFAULT_PRECISION)
+    }
+
+    // scale returns the number of smallest units in one whole unit of the asset
+    pub fn scale(&self, currency: Currency) -> u128 {
+        10u128.pow(self.precision(currency))
+    }
+
+    // parse_amount parses a decimal amount of the given asset into its internal representation
+    // Like the engine always did, exponent notation is accepted and decimal places beyond the precision of the asset are
+    // truncated, e.g. `1.23456` euro is 1.2345 euro
+    pub fn parse_amount(&self, currency: Currency, amount: &str) -> Result<u128> {
+        let invalid = || Error::InvalidAmount(amount.to_string());
+        let (mantissa, exponent) = match amount.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => (mantissa, exponent.parse::<i32>().map_err(|_| invalid())?),
+            None => (amount, 0),
+        };
+        let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        if (whole.is_empty() && fraction.is_empty())
+            || !whole.bytes().chain(fraction.bytes()).all(|b| b.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+        // the amount in the smallest unit consists of the digits up to the decimal point, after it's moved by the exponent
+        // and the precision, leading zeros don't count
+        let digits = format!("{}{}", whole, fraction);
+        let significant = digits.trim_start_matches('0');
+        let end = whole.len() as i64 - (digits.len() - significant.len()) as i64
+            + exponent as i64
+            + self.precision(currency) as i64;
+        match end {
+            end if end <= 0 || significant.is_empty() => Ok(0),
+            // u128 has 39 digits at most
+            end if end > 39 => Err(invalid()),
+            end => {
+                let end = end as usize;
+                let units = match significant.get(..end) {
+                    Some(units) => units.to_string(),
+                    None => format!("{:0<width$}", significant, width = end),
+                };
+                units.parse().map_err(|_| invalid())
+            }
+        }
+    }
+
+    // format_amount formats an amount in the internal representation of the given asset the way floats are printed:
+    // without trailing zeros, but with at least one decimal place if the asset has any
+    pub fn format_amount(&self, currency: Currency, amount: i128) -> String {
+        let sign = if amount < 0 { "-" } else { "" };
+        let (scale, precision) = (self.scale(currency), self.precision(currency) as usize);
+        let amount = amount.unsigned_abs();
+        if precision == 0 {
+            return format!("{}{}", sign, amount);
+        }
+        let fraction = format!("{:0width$}", amount % scale, width = precision);
+        let fraction = match fraction.trim_end_matches('0') {
+            "" => "0",
+            fraction => fraction,
+        };
+        format!("{}{}.{}", sign, amount / scale, fraction)
+    }
+
+    // transaction converts an input row into the internal representation of its asset
+    pub fn transaction(&self, row: TransactionRow) -> Result<Transaction> {
+        let currency = row.currency.unwrap_or_default();
+        Ok(Transaction {
+            type_: row.type_,
+            client: row.client,
+            tx: row.tx,
+            amount: row.amount.map(|amount| self.parse_amount(currency, &amount)).transpose()?,
+            currency,
+            destination: row.destination,
+            fee: 0,
+            charged_back: 0,
+            timestamp: row.timestamp,
+        })
+    }
+
+    // account_row converts an account into an output row, with the balances formatted for its asset
+    pub fn account_row(&self, account: Account) -> AccountRow {
+        AccountRow {
+            id: account.id,
+            currency: Some(account.currency),
+            available: self.format_amount(account.currency, account.available),
+            held: self.format_amount(account.currency, account.held),
+            total: self.format_amount(account.currency, account.total),
+            locked: account.locked,
+            pending_credit: None,
+            deficit: None,
+        }
+    }
+
+    // account_rows converts accounts into output rows
+    // The currency column is only written if any of the accounts is in another currency than the default one, so the
+    // output of input without currencies keeps its format
+    pub fn account_rows<I: IntoIterator<Item = Account>>(&self, accounts: I) -> Vec<AccountRow> {
+        let mut rows: Vec<AccountRow> = accounts.into_iter().map(|account| self.account_row(account)).collect();
+        if rows.iter().all(|row| row.currency == Some(Currency::DEFAULT)) {
+            rows.iter_mut().for_each(|row| row.currency = None);
+        }
+        rows
+    }
+}
+
+impl FromStr for Assets {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut assets = Assets::default();
+        for (idx, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: String| Error::InvalidAssets(format!("line {}: {}", idx + 1, reason));
+            let (code, precision) = line
+                .split_once('=')
+                .ok_or_else(|| invalid("expected `CODE = decimal places`".to_string()))?;
+            let currency = Currency::new(code.trim()).map_err(|_| invalid(format!("invalid code `{}`", code.trim())))?;
+            let precision = precision
+                .trim()
+                .parse::<Precision>()
+                .ok()
+                .filter(|p| *p <= MAX_PRECISION)
+                .ok_or_else(|| invalid(format!("decimal places must be 0 to {}", MAX_PRECISION)))?;
+            assets.precisions.insert(currency, precision);
+        }
+        Ok(assets)
+    }
+}
+
+// the registry is written in the file format, so it can be persisted and loaded again
+impl fmt::Display for Assets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (currency, precision) in &self.precisions {
+            writeln!(f, "{} = {}", currency, precision)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amounts() -> Result<()> {
+        let assets: Assets = "# assets of the test\njpy = 0\n BTC=8\nETH = 18\n".parse()?;
+        assert_eq!(assets.to_string().parse::<Assets>()?, assets);
+        let (eur, jpy, btc, eth) = (Currency::DEFAULT, Currency::new("JPY")?, Currency::new("BTC")?, Currency::new("ETH")?);
+
+        assert_eq!(assets.parse_amount(eur, "1.5")?, 15000);
+        assert_eq!(assets.parse_amount(eur, ".25")?, 2500);
+        assert_eq!(assets.parse_amount(eur, "3")?, 30000);
+        assert_eq!(assets.parse_amount(jpy, "1200")?, 1200);
+        assert_eq!(assets.parse_amount(jpy, "1200.00")?, 1200);
+        assert_eq!(assets.parse_amount(btc, "0.00000001")?, 1);
+        assert_eq!(assets.parse_amount(eth, "12345678901234567890.123456789012345678")?, 12345678901234567890123456789012345678);
+        assert_eq!(assets.parse_amount(eur, "0012.5e-1")?, 12500);
+        for invalid in ["", ".", "e3", "1e", "1.5e3.0", "-1.0", "1,5", "1.2.3"] {
+            assert!(assets.parse_amount(eur, invalid).is_err(), "{}", invalid);
+        }
+        assert!(assets.parse_amount(eth, "1000000000000000000000").is_err());
+        assert!(assets.parse_amount(eur, "1e36").is_err());
+
+        // like the engine always did, decimal places beyond the precision are truncated and exponents are accepted
+        assert_eq!(assets.parse_amount(eur, "1.23456")?, 12345);
+        assert_eq!(assets.parse_amount(eur, "0.00009")?, 0);
+        assert_eq!(assets.parse_amount(eur, "12.5e3")?, 125_000_000);
+        assert_eq!(assets.parse_amount(eur, "2.5E-4")?, 2);
+        assert_eq!(assets.parse_amount(eur, "1e-9999")?, 0);
+        assert_eq!(assets.parse_amount(jpy, "1.5")?, 1);
+
+        assert_eq!(assets.format_amount(eur, 15000), "1.5");
+        assert_eq!(assets.format_amount(eur, 0), "0.0");
+        assert_eq!(assets.format_amount(eur, -25), "-0.0025");
+        assert_eq!(assets.format_amount(jpy, 1200), "1200");
+        assert_eq!(assets.format_amount(btc, 150000000), "1.5");
+        assert_eq!(assets.format_amount(eth, 12345678901234567890123456789012345678), "12345678901234567890.123456789012345678");
+
+        assert!("JPY = 19".parse::<Assets>().is_err());
+        assert!("JPY = -1".parse::<Assets>().is_err());
+        assert!("J-Y = 2".parse::<Assets>().is_err());
+        assert!("JPY".parse::<Assets>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_account_rows() -> Result<()> {
+        let assets = Assets::default();
+        let eur = Account { id: 1, ..Default::default() };
+        let usd = Account { id: 1, currency: Currency::new("USD")?, ..Default::default() };
+
+        // the currency column is only there if it tells the accounts apart
+        let rows = assets.account_rows([eur.clone()]);
+        assert_eq!(rows[0].currency, None);
+        let rows = assets.account_rows([eur, usd]);
+        assert_eq!(rows.iter().map(|row| row.currency).collect::<Vec<_>>(), vec![Some(Currency::DEFAULT), Some(Currency::new("USD")?)]);
+        Ok(())
+    }
+}
@@ -0,0 +1,383 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::{
+    error::{Error, Result},
+    generate::{Config as GenerateConfig, Mix},
+    history::SequenceNumber,
+    logging::LogOptions,
+    types::{ClientID, TransactionID},
+    verify::VerifyMode,
+};
+
+// by default a snapshot of all accounts is written to the history every 100k transactions
+pub const DEFAULT_SNAPSHOT_INTERVAL: SequenceNumber = 100_000;
+
+// Command is the parsed command line of the application
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    // process a transaction file and print the final account states
+    Process(ProcessOptions),
+    // print the state of an account at a given point in a recorded history
+    At(AtOptions),
+    // generate deterministic test data
+    Generate(GenerateOptions),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessOptions {
+    pub input: PathBuf,
+    // if set, the processed transactions and periodic snapshots are persisted into this directory
+    pub history: Option<PathBuf>,
+    pub snapshot_interval: SequenceNumber,
+    pub verify: VerifyMode,
+    // if set, the business rules for disputes and chargebacks are loaded from this file
+    pub policy: Option<PathBuf>,
+    // if set, the precision of the currencies and assets is loaded from this file
+    pub assets: Option<PathBuf>,
+    // if set, the fees charged on deposits and withdrawals are loaded from this file
+    pub fees: Option<PathBuf>,
+    // if set, the risk limits on withdrawals are loaded from this file
+    pub limits: Option<PathBuf>,
+    // if set, the metrics of the run are written to this file in the Prometheus text format
+    pub metrics: Option<PathBuf>,
+    // if set, the metrics are served on this address while the run is in progress
+    pub metrics_addr: Option<SocketAddr>,
+    // if set, the result of every transaction and the fees charged on it are written to this file
+    pub results: Option<PathBuf>,
+    // if set, a summary report is written to this file at the end of the run, `-` means stderr
+    pub summary: Option<PathBuf>,
+    // if set, the output has a column with the pending credit of the disputed withdrawals
+    pub pending_credit: bool,
+    // if set, the output has a column that flags the accounts in deficit
+    pub deficit: bool,
+}
+
+// Point identifies a point in time in a recorded history
+#[derive(Debug, Clone, PartialEq)]
+pub enum Point {
+    // right after the transaction with the given sequence number
+    Seq(SequenceNumber),
+    // right after the deposit or withdrawal with the given transaction id
+    Tx(TransactionID),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AtOptions {
+    pub history: PathBuf,
+    pub client: ClientID,
+    pub point: Point,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerateOptions {
+    pub config: GenerateConfig,
+    // where to write the transactions to, defaults to stdout
+    pub output: Option<PathBuf>,
+    // where to write the expected final account states to
+    pub expected: Option<PathBuf>,
+}
+
+pub fn usage(program: &str) -> String {
+    format!(
+        "Usage:\n  \
+        {0} [--log-level <error|warn|info|debug|trace>] [--log-file <file>] <command>\n\n\
+        Commands:\n  \
+        {0} [--history <dir>] [--snapshot-interval <n>] [--verify <each|end>] [--policy <file>]\n    \
+        [--assets <file>] [--fees <file>] [--limits <file>] [--metrics <file>] [--metrics-addr <addr>]\n    \
+        [--summary <file|->] [--results <file>] [--pending-credit] [--deficit] <transaction-csv-file>\n  \
+        {0} at <history-dir> <client> (<seq> | --tx <tx>)\n  \
+        {0} generate [--seed <n>] [--clients <n>] [--rows <n>] [--mix <deposit,withdrawal,dispute,resolve,chargeback>]\n    \
+        [--invalid-rate <0..1>] [--output <file>] [--expected <file>]",
+        program
+    )
+}
+
+// parse_log_options removes the logging options from the arguments and returns them
+// they are accepted anywhere on the command line, since they apply to all commands
+pub fn parse_log_options(args: &mut Vec<String>) -> Result<LogOptions> {
+    let mut opts = LogOptions::default();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--log-level" => {
+                opts.level = value(args.get(i + 1))?
+                    .parse()
+                    .map_err(|_| Error::InvalidArguments)?
+            }
+            "--log-file" => opts.file = Some(PathBuf::from(value(args.get(i + 1))?)),
+            _ => {
+                i += 1;
+                continue;
+            }
+        }
+        args.drain(i..i + 2);
+    }
+    Ok(opts)
+}
+
+// parse parses the command line arguments (without the program name)
+pub fn parse(args: &[String]) -> Result<Command> {
+    match args.first().map(String::as_str) {
+        Some("at") => return parse_at(&args[1..]),
+        Some("generate") => return parse_generate(&args[1..]),
+        _ => {}
+    }
+
+    let mut input = None;
+    let mut history = None;
+    let mut snapshot_interval = DEFAULT_SNAPSHOT_INTERVAL;
+    let mut verify = VerifyMode::Off;
+    let mut policy = None;
+    let mut assets = None;
+    let mut fees = None;
+    let mut limits = None;
+    let mut metrics = None;
+    let mut metrics_addr = None;
+    let mut summary = None;
+    let mut results = None;
+    let mut pending_credit = false;
+    let mut deficit = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--history" => history = Some(PathBuf::from(value(args.next())?)),
+            "--snapshot-interval" => snapshot_interval = number(args.next())?,
+            "--verify" => {
+                verify = match value(args.next())? {
+                    "each" => VerifyMode::EachTransaction,
+                    "end" => VerifyMode::End,
+                    _ => return Err(Error::InvalidArguments),
+                }
+            }
+            "--policy" => policy = Some(PathBuf::from(value(args.next())?)),
+            "--assets" => assets = Some(PathBuf::from(value(args.next())?)),
+            "--fees" => fees = Some(PathBuf::from(value(args.next())?)),
+            "--limits" => limits = Some(PathBuf::from(value(args.next())?)),
+            "--metrics" => metrics = Some(PathBuf::from(value(args.next())?)),
+            "--metrics-addr" => metrics_addr = Some(number(args.next())?),
+            "--summary" => summary = Some(PathBuf::from(value(args.next())?)),
+            "--results" => results = Some(PathBuf::from(value(args.next())?)),
+            "--pending-credit" => pending_credit = true,
+            "--deficit" => deficit = true,
+            _ if arg.starts_with("--") || input.is_some() => return Err(Error::InvalidArguments),
+            _ => input = Some(PathBuf::from(arg)),
+        }
+    }
+
+    Ok(Command::Process(ProcessOptions {
+        input: input.ok_or(Error::InvalidArguments)?,
+        history,
+        snapshot_interval,
+        verify,
+        policy,
+        assets,
+        fees,
+        limits,
+        metrics,
+        metrics_addr,
+        summary,
+        results,
+        pending_credit,
+        deficit,
+    }))
+}
+
+fn parse_at(args: &[String]) -> Result<Command> {
+    let (history, client, point) = match args {
+        [history, client, flag, tx] if flag == "--tx" => {
+            (history, client, Point::Tx(number(Some(tx))?))
+        }
+        [history, client, seq] => (history, client, Point::Seq(number(Some(seq))?)),
+        _ => return Err(Error::InvalidArguments),
+    };
+    Ok(Command::At(AtOptions {
+        history: PathBuf::from(history),
+        client: number(Some(client))?,
+        point,
+    }))
+}
+
+fn parse_generate(args: &[String]) -> Result<Command> {
+    let mut opts = GenerateOptions {
+        config: GenerateConfig::default(),
+        output: None,
+        expected: None,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => opts.config.seed = number(args.next())?,
+            "--clients" => opts.config.clients = number(args.next())?,
+            "--rows" => opts.config.rows = number(args.next())?,
+            "--mix" => opts.config.mix = mix(value(args.next())?)?,
+            "--invalid-rate" => {
+                opts.config.invalid_rate = number(args.next())?;
+                if !(0.0..=1.0).contains(&opts.config.invalid_rate) {
+                    return Err(Error::InvalidArguments);
+                }
+            }
+            "--output" => opts.output = Some(PathBuf::from(value(args.next())?)),
+            "--expected" => opts.expected = Some(PathBuf::from(value(args.next())?)),
+            _ => return Err(Error::InvalidArguments),
+        }
+    }
+    Ok(Command::Generate(opts))
+}
+
+// mix parses the comma separated weights of deposits, withdrawals, disputes, resolves and chargebacks
+fn mix(arg: &str) -> Result<Mix> {
+    let weights = arg
+        .split(',')
+        .map(|w| w.trim().parse::<u32>().map_err(|_| Error::InvalidArguments))
+        .collect::<Result<Vec<u32>>>()?;
+    match weights[..] {
+        [deposit, withdrawal, dispute, resolve, chargeback] => Ok(Mix {
+            deposit,
+            withdrawal,
+            dispute,
+            resolve,
+            chargeback,
+        }),
+        _ => Err(Error::InvalidArguments),
+    }
+}
+
+fn value(arg: Option<&String>) -> Result<&str> {
+    arg.map(String::as_str).ok_or(Error::InvalidArguments)
+}
+
+fn number<N: std::str::FromStr>(arg: Option<&String>) -> Result<N> {
+    value(arg)?.parse().map_err(|_| Error::InvalidArguments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(&args(&["input.csv"])).unwrap(),
+            Command::Process(ProcessOptions {
+                input: PathBuf::from("input.csv"),
+                history: None,
+                snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+                verify: VerifyMode::Off,
+                policy: None,
+                assets: None,
+                fees: None,
+                limits: None,
+                metrics: None,
+                metrics_addr: None,
+                summary: None,
+                results: None,
+                pending_credit: false,
+                deficit: false,
+            })
+        );
+        assert_eq!(
+            parse(&args(&[
+                "--history",
+                "h",
+                "--snapshot-interval",
+                "10",
+                "--verify",
+                "end",
+                "--policy",
+                "p.conf",
+                "--assets",
+                "a.conf",
+                "--fees",
+                "f.conf",
+                "--limits",
+                "l.conf",
+                "--metrics",
+                "m.prom",
+                "--metrics-addr",
+                "127.0.0.1:9000",
+                "--summary",
+                "-",
+                "--results",
+                "r.csv",
+                "--pending-credit",
+                "--deficit",
+                "input.csv"
+            ]))
+            .unwrap(),
+            Command::Process(ProcessOptions {
+                input: PathBuf::from("input.csv"),
+                history: Some(PathBuf::from("h")),
+                snapshot_interval: 10,
+                verify: VerifyMode::End,
+                policy: Some(PathBuf::from("p.conf")),
+                assets: Some(PathBuf::from("a.conf")),
+                fees: Some(PathBuf::from("f.conf")),
+                limits: Some(PathBuf::from("l.conf")),
+                metrics: Some(PathBuf::from("m.prom")),
+                metrics_addr: Some("127.0.0.1:9000".parse().unwrap()),
+                summary: Some(PathBuf::from("-")),
+                results: Some(PathBuf::from("r.csv")),
+                pending_credit: true,
+                deficit: true,
+            })
+        );
+        assert_eq!(
+            parse(&args(&["at", "h", "42", "1000"])).unwrap(),
+            Command::At(AtOptions {
+                history: PathBuf::from("h"),
+                client: 42,
+                point: Point::Seq(1000),
+            })
+        );
+        assert_eq!(
+            parse(&args(&["at", "h", "42", "--tx", "7"])).unwrap(),
+            Command::At(AtOptions {
+                history: PathBuf::from("h"),
+                client: 42,
+                point: Point::Tx(7),
+            })
+        );
+        assert_eq!(
+            parse(&args(&["generate", "--seed", "3", "--mix", "1,2,3,4,5", "--expected", "e.csv"])).unwrap(),
+            Command::Generate(GenerateOptions {
+                config: GenerateConfig {
+                    seed: 3,
+                    mix: Mix {
+                        deposit: 1,
+                        withdrawal: 2,
+                        dispute: 3,
+                        resolve: 4,
+                        chargeback: 5,
+                    },
+                    ..Default::default()
+                },
+                output: None,
+                expected: Some(PathBuf::from("e.csv")),
+            })
+        );
+        assert!(parse(&args(&[])).is_err());
+
+        let mut log_args = args(&["--log-level", "debug", "input.csv", "--log-file", "log.txt"]);
+        assert_eq!(
+            parse_log_options(&mut log_args).unwrap(),
+            LogOptions {
+                level: tracing::Level::DEBUG,
+                file: Some(PathBuf::from("log.txt")),
+            }
+        );
+        assert_eq!(log_args, args(&["input.csv"]));
+        assert!(parse_log_options(&mut args(&["--log-level", "loud"])).is_err());
+        assert!(parse(&args(&["generate", "--mix", "1,2,3"])).is_err());
+        assert!(parse(&args(&["generate", "--invalid-rate", "2"])).is_err());
+        assert!(parse(&args(&["a.csv", "b.csv"])).is_err());
+        assert!(parse(&args(&["--unknown", "a.csv"])).is_err());
+        assert!(parse(&args(&["--verify", "sometimes", "a.csv"])).is_err());
+        assert!(parse(&args(&["--metrics-addr", "nowhere", "a.csv"])).is_err());
+        assert!(parse(&args(&["at", "h", "not-a-client", "1"])).is_err());
+    }
+}
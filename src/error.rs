@@ -1,15 +1,75 @@
+use crate::limits::Limit;
+
 #[derive(Debug)]
 pub enum Error {
     InvalidArguments,
     IO(std::io::Error),
     Join(tokio::task::JoinError),
+    Csv(csv_async::Error),
     InsufficientFunds,
+    Overflow,
     AccountLocked,
+    AccountFrozen,
+    AccountClosed,
+    AccountNotEmpty,
     NotFound,
+    InvariantViolation(String),
+    InvalidPolicy(String),
+    InvalidCurrency(String),
+    InvalidAssets(String),
+    InvalidAmount(String),
+    InvalidTransfer,
+    InvalidFees(String),
+    InvalidLimits(String),
+    ExceedsAuthorization,
+    ExceedsUndisputed,
+    ExceedsDisputed,
+    AlreadyDisputed,
+    DisputeWindowClosed,
+    NotDisputed,
+    LimitExceeded(Limit),
+    Rejected(String),
+    NotPermitted,
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+impl Error {
+    // kind returns a short machine readable name of the error, e.g. for metric labels
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Self::InvalidArguments => "invalid_arguments",
+            Self::IO(_) => "io",
+            Self::Join(_) => "join",
+            Self::Csv(_) => "csv",
+            Self::InsufficientFunds => "insufficient_funds",
+            Self::Overflow => "overflow",
+            Self::AccountLocked => "account_locked",
+            Self::AccountFrozen => "account_frozen",
+            Self::AccountClosed => "account_closed",
+            Self::AccountNotEmpty => "account_not_empty",
+            Self::NotFound => "not_found",
+            Self::InvariantViolation(_) => "invariant_violation",
+            Self::InvalidPolicy(_) => "invalid_policy",
+            Self::InvalidCurrency(_) => "invalid_currency",
+            Self::InvalidAssets(_) => "invalid_assets",
+            Self::InvalidAmount(_) => "invalid_amount",
+            Self::InvalidTransfer => "invalid_transfer",
+            Self::InvalidFees(_) => "invalid_fees",
+            Self::InvalidLimits(_) => "invalid_limits",
+            Self::ExceedsAuthorization => "exceeds_authorization",
+            Self::ExceedsUndisputed => "exceeds_undisputed",
+            Self::ExceedsDisputed => "exceeds_disputed",
+            Self::AlreadyDisputed => "already_disputed",
+            Self::DisputeWindowClosed => "dispute_window_closed",
+            Self::NotDisputed => "not_disputed",
+            Self::LimitExceeded(_) => "limit_exceeded",
+            Self::Rejected(_) => "rejected",
+            Self::NotPermitted => "not_permitted",
+        }
+    }
+}
+
 impl std::error::Error for Error {}
 
 impl std::fmt::Display for Error {
@@ -17,10 +77,32 @@ impl std::fmt::Display for Error {
         match *self {
             Self::InvalidArguments => write!(f, "invalid arguments"),
             Self::InsufficientFunds => write!(f, "insufficient funds"),
+            Self::Overflow => write!(f, "amount overflow"),
             Self::IO(ref e) => write!(f, "io error: {}", e),
             Self::Join(ref e) => write!(f, "join error: {}", e),
+            Self::Csv(ref e) => write!(f, "csv error: {}", e),
             Self::AccountLocked => write!(f, "account locked"),
+            Self::AccountFrozen => write!(f, "account frozen"),
+            Self::AccountClosed => write!(f, "account closed"),
+            Self::AccountNotEmpty => write!(f, "account not empty"),
             Self::NotFound => write!(f, "not found"),
+            Self::InvariantViolation(ref reason) => write!(f, "invariant violated: {}", reason),
+            Self::InvalidPolicy(ref reason) => write!(f, "invalid policy: {}", reason),
+            Self::InvalidCurrency(ref code) => write!(f, "invalid currency `{}`", code),
+            Self::InvalidAssets(ref reason) => write!(f, "invalid assets: {}", reason),
+            Self::InvalidAmount(ref amount) => write!(f, "invalid amount `{}`", amount),
+            Self::InvalidTransfer => write!(f, "transfer without a destination other than the source"),
+            Self::InvalidFees(ref reason) => write!(f, "invalid fees: {}", reason),
+            Self::InvalidLimits(ref reason) => write!(f, "invalid limits: {}", reason),
+            Self::ExceedsAuthorization => write!(f, "capture exceeds the authorized amount"),
+            Self::ExceedsUndisputed => write!(f, "dispute exceeds the undisputed amount of the transaction"),
+            Self::ExceedsDisputed => write!(f, "amount exceeds the disputed amount of the transaction"),
+            Self::AlreadyDisputed => write!(f, "transaction is already disputed"),
+            Self::DisputeWindowClosed => write!(f, "transaction is too old to be disputed"),
+            Self::NotDisputed => write!(f, "transaction is not disputed"),
+            Self::LimitExceeded(limit) => write!(f, "withdrawal exceeds the `{}` limit", limit),
+            Self::Rejected(ref reason) => write!(f, "rejected by validator: {}", reason),
+            Self::NotPermitted => write!(f, "not permitted by policy"),
         }
     }
 }
@@ -36,3 +118,9 @@ impl From<tokio::task::JoinError> for Error {
         Self::Join(err)
     }
 }
+
+impl From<csv_async::Error> for Error {
+    fn from(err: csv_async::Error) -> Self {
+        Self::Csv(err)
+    }
+}
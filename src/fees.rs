@@ -0,0 +1,316 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{
+    assets::{Assets, Precision, MAX_PRECISION},
+    error::{Error, Result},
+    types::{ClientID, Currency, TxType},
+};
+
+// by default the fees are collected on the account of the highest client id
+pub const DEFAULT_HOUSE: ClientID = ClientID::MAX;
+
+// Decimal is a non-negative decimal number as written in a fee schedule, e.g. `0.25` is 25 with a scale of 2
+// Fee schedules are independent of the assets registry, so their amounts are only converted into the
+// internal representation once the currency of the transaction is known
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) struct Decimal {
+    digits: u128,
+    scale: u32,
+}
+
+impl Decimal {
+    // units converts the number into the internal representation of an asset with the given precision,
+    // rounded half up to its smallest unit
+    pub(crate) fn units(&self, precision: Precision) -> u128 {
+        if self.scale <= precision {
+            return self.digits.saturating_mul(10u128.pow(precision - self.scale));
+        }
+        divide_rounded(self.digits, 10u128.pow(self.scale - precision))
+    }
+
+    // percent_of returns this percentage of the amount, rounded half up to the smallest unit
+    fn percent_of(&self, amount: u128) -> u128 {
+        let denominator = 100 * 10u128.pow(self.scale);
+        let (whole, rest) = (amount / denominator, amount % denominator);
+        // the rest is smaller than the denominator, so the product only overflows for absurd percentages
+        whole
+            .saturating_mul(self.digits)
+            .saturating_add(divide_rounded(rest.saturating_mul(self.digits), denominator))
+    }
+}
+
+// divide_rounded divides rounding half up
+fn divide_rounded(dividend: u128, divisor: u128) -> u128 {
+    dividend / divisor + u128::from(dividend % divisor >= divisor.div_ceil(2))
+}
+
+impl FromStr for Decimal {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidFees(format!("invalid number `{}`", s));
+        let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+        let fraction = fraction.trim_end_matches('0');
+        if (whole.is_empty() && fraction.is_empty())
+            || !whole.bytes().chain(fraction.bytes()).all(|b| b.is_ascii_digit())
+            || fraction.len() > MAX_PRECISION as usize
+        {
+            return Err(invalid());
+        }
+        Ok(Self {
+            digits: format!("0{}{}", whole, fraction).parse().map_err(|_| invalid())?,
+            scale: fraction.len() as u32,
+        })
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scale = 10u128.pow(self.scale);
+        match self.scale {
+            0 => write!(f, "{}", self.digits),
+            _ => write!(f, "{}.{:0width$}", self.digits / scale, self.digits % scale, width = self.scale as usize),
+        }
+    }
+}
+
+// Fee is a flat amount plus a percentage of the transaction amount, limited to at least `min` and at most `max`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Fee {
+    flat: Decimal,
+    percent: Decimal,
+    min: Option<Decimal>,
+    max: Option<Decimal>,
+}
+
+impl Fee {
+    // amount returns the fee of a transaction of the given amount in the internal representation of its asset
+    fn amount(&self, precision: Precision, amount: u128) -> u128 {
+        let mut fee = self.flat.units(precision).saturating_add(self.percent.percent_of(amount));
+        if let Some(min) = self.min {
+            fee = fee.max(min.units(precision));
+        }
+        if let Some(max) = self.max {
+            fee = fee.min(max.units(precision));
+        }
+        fee
+    }
+}
+
+impl FromStr for Fee {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut fee = Fee::default();
+        let mut tokens = s.split_whitespace();
+        let mut empty = true;
+        while let Some(token) = tokens.next() {
+            empty = false;
+            let mut next = || tokens.next().ok_or_else(|| Error::InvalidFees(format!("missing amount after `{}`", token)));
+            match token {
+                "+" => {}
+                "min" => fee.min = Some(next()?.parse()?),
+                "max" => fee.max = Some(next()?.parse()?),
+                percent if percent.ends_with('%') => fee.percent = percent.trim_end_matches('%').parse()?,
+                flat => fee.flat = flat.parse()?,
+            }
+        }
+        if empty {
+            return Err(Error::InvalidFees("missing fee".to_string()));
+        }
+        Ok(fee)
+    }
+}
+
+impl fmt::Display for Fee {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} + {}%", self.flat, self.percent)?;
+        if let Some(min) = self.min {
+            write!(f, " min {}", min)?;
+        }
+        if let Some(max) = self.max {
+            write!(f, " max {}", max)?;
+        }
+        Ok(())
+    }
+}
+
+// Rule is the fee of the transactions of a type, optionally only in one currency and only from an amount on
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    type_: TxType,
+    currency: Option<Currency>,
+    from: Decimal,
+    fee: Fee,
+}
+
+// FeeSchedule holds the fees charged on deposits and withdrawals and where they go
+//
+// A fee schedule file has one `key = value` pair per line, empty lines and lines starting with `#` are ignored:
+//
+//   house = <client id>                           the account the fees are posted to
+//   refund_on_chargeback = true | false           a chargeback refunds the fee of the charged back transaction
+//   <deposit|withdrawal>[.<CURRENCY>] [from <amount>] = [<amount>] [+ <percent>%] [min <amount>] [max <amount>]
+//
+// Rules for a currency replace the rules without a currency, and of the remaining rules the one with the highest
+// `from` that is not above the amount of the transaction applies (tiers). Amounts are rounded to the precision of the currency.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeeSchedule {
+    pub house: ClientID,
+    pub refund_on_chargeback: bool,
+    rules: Vec<Rule>,
+}
+
+impl Default for FeeSchedule {
+    fn default() -> Self {
+        Self {
+            house: DEFAULT_HOUSE,
+            refund_on_chargeback: false,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl FeeSchedule {
+    // load reads the fee schedule from the given file
+    pub async fn load(path: &Path) -> Result<Self> {
+        tokio::fs::read_to_string(path).await?.parse()
+    }
+
+    // fee returns the fee of a transaction in the internal representation of its currency
+    pub fn fee(&self, assets: &Assets, type_: &TxType, currency: Currency, amount: u128) -> u128 {
+        let precision = assets.precision(currency);
+        let rules = self.rules.iter().filter(|rule| rule.type_ == *type_);
+        let specific = rules.clone().any(|rule| rule.currency == Some(currency));
+        rules
+            .filter(|rule| rule.currency == specific.then_some(currency))
+            .map(|rule| (rule.from.units(precision), rule))
+            .filter(|(from, _)| *from <= amount)
+            .max_by_key(|(from, _)| *from)
+            .map_or(0, |(_, rule)| rule.fee.amount(precision, amount))
+    }
+}
+
+impl FromStr for FeeSchedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut schedule = FeeSchedule::default();
+        for (idx, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: String| Error::InvalidFees(format!("line {}: {}", idx + 1, reason));
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid("expected `key = value`".to_string()))?;
+            let value = value.trim();
+            let res = match key.trim() {
+                "house" => value
+                    .parse()
+                    .map(|house| schedule.house = house)
+                    .map_err(|_| Error::InvalidFees(format!("invalid client id `{}`", value))),
+                "refund_on_chargeback" => value
+                    .parse()
+                    .map(|refund| schedule.refund_on_chargeback = refund)
+                    .map_err(|_| Error::InvalidFees(format!("expected `true` or `false`, got `{}`", value))),
+                key => parse_rule(key, value).map(|rule| schedule.rules.push(rule)),
+            };
+            res.map_err(|err| match err {
+                Error::InvalidFees(reason) => invalid(reason),
+                err => err,
+            })?;
+        }
+        Ok(schedule)
+    }
+}
+
+// parse_rule parses a rule from its key, `<type>[.<CURRENCY>] [from <amount>]`, and its fee
+fn parse_rule(key: &str, fee: &str) -> Result<Rule> {
+    let (target, from) = match key.split_once(char::is_whitespace) {
+        Some((target, from)) => match from.trim().strip_prefix("from") {
+            Some(amount) => (target, amount.trim().parse()?),
+            None => return Err(Error::InvalidFees(format!("unknown key `{}`", key))),
+        },
+        None => (key, Decimal::default()),
+    };
+    let (type_, currency) = match target.split_once('.') {
+        Some((type_, currency)) => (type_, Some(Currency::new(currency)?)),
+        None => (target, None),
+    };
+    let type_ = match TxType::from_name(type_) {
+        Some(type_ @ (TxType::Deposit | TxType::Withdrawal)) => type_,
+        _ => return Err(Error::InvalidFees(format!("unknown key `{}`", key))),
+    };
+    Ok(Rule {
+        type_,
+        currency,
+        from,
+        fee: fee.parse()?,
+    })
+}
+
+// the schedule is written in the file format, so it can be persisted and loaded again
+impl fmt::Display for FeeSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "house = {}", self.house)?;
+        writeln!(f, "refund_on_chargeback = {}", self.refund_on_chargeback)?;
+        for rule in &self.rules {
+            write!(f, "{}", rule.type_.as_str())?;
+            if let Some(currency) = rule.currency {
+                write!(f, ".{}", currency)?;
+            }
+            writeln!(f, " from {} = {}", rule.from, rule.fee)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fees() -> Result<()> {
+        let schedule: FeeSchedule = "\
+            # fees of the test\n\
+            house = 9\n\
+            withdrawal = 0.5 + 1% min 1 max 5\n\
+            withdrawal from 1000 = 0.5%\n\
+            deposit.USD = 2.5%\n\
+            withdrawal.JPY = 100\n"
+            .parse()?;
+        assert_eq!(schedule.house, 9);
+        assert!(!schedule.refund_on_chargeback);
+        assert_eq!(schedule.to_string().parse::<FeeSchedule>()?, schedule);
+
+        let assets: Assets = "JPY = 0".parse()?;
+        let (eur, usd, jpy) = (Currency::DEFAULT, Currency::new("USD")?, Currency::new("JPY")?);
+        let fee = |type_, currency, amount| schedule.fee(&assets, &type_, currency, amount);
+        // 0.5 + 1% of 10 is 0.6, but at least 1
+        assert_eq!(fee(TxType::Withdrawal, eur, 100_000), 10_000);
+        // 0.5 + 1% of 200 is 2.5
+        assert_eq!(fee(TxType::Withdrawal, eur, 2_000_000), 25_000);
+        // at most 5
+        assert_eq!(fee(TxType::Withdrawal, eur, 9_000_000), 50_000);
+        // from 1000 on only 0.5%
+        assert_eq!(fee(TxType::Withdrawal, eur, 20_000_000), 100_000);
+        // 2.5% of 0.0001 is rounded to the smallest unit
+        assert_eq!(fee(TxType::Deposit, usd, 1), 0);
+        assert_eq!(fee(TxType::Deposit, usd, 20), 1);
+        assert_eq!(fee(TxType::Deposit, eur, 1_000_000), 0);
+        // the rule for yen replaces the others
+        assert_eq!(fee(TxType::Withdrawal, jpy, 1_000_000), 100);
+
+        assert!("transfer = 1".parse::<FeeSchedule>().is_err());
+        assert!("withdrawal = 1 min".parse::<FeeSchedule>().is_err());
+        assert!("withdrawal = ".parse::<FeeSchedule>().is_err());
+        assert!("withdrawal to 5 = 1".parse::<FeeSchedule>().is_err());
+        assert!("withdrawal = -1%".parse::<FeeSchedule>().is_err());
+        assert!("house = bank".parse::<FeeSchedule>().is_err());
+        Ok(())
+    }
+}
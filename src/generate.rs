@@ -0,0 +1,390 @@
+use csv_async::AsyncSerializer;
+use std::collections::BTreeMap;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+
+use crate::{
+    assets::Assets,
+    error::Result,
+    types::{Account, AccountKey, ClientID, Currency, Transaction, TransactionID, TxType},
+};
+
+// Mix holds the relative weights of the transaction types in the generated data
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mix {
+    pub deposit: u32,
+    pub withdrawal: u32,
+    pub dispute: u32,
+    pub resolve: u32,
+    pub chargeback: u32,
+}
+
+impl Default for Mix {
+    fn default() -> Self {
+        Self {
+            deposit: 50,
+            withdrawal: 35,
+            dispute: 8,
+            resolve: 6,
+            chargeback: 1,
+        }
+    }
+}
+
+// Config describes the data to generate, the same config always generates the same data
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub seed: u64,
+    pub clients: ClientID,
+    pub rows: u64,
+    pub mix: Mix,
+    // the probability of a row being an invalid scenario that the engine has to reject
+    pub invalid_rate: f64,
+    // the maximum amount of a deposit in the internal representation of the default currency (the actual amount * 10000)
+    pub max_amount: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            clients: 100,
+            rows: 10_000,
+            mix: Mix::default(),
+            invalid_rate: 0.05,
+            max_amount: 1000 * 10000,
+        }
+    }
+}
+
+// Rng is a small splitmix64 generator
+// It is implemented here so the generated data stays the same across versions of external crates
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // below returns a number in 0..n
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    // between returns a number in from..=to
+    fn between(&mut self, from: u64, to: u64) -> u64 {
+        from + self.below(to - from + 1)
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+// the state the generator keeps per client to only produce sensible transactions
+#[derive(Debug, Clone, Default)]
+struct ClientState {
+    account: Account,
+    touched: bool,
+    // deposits that can still be disputed
+    open: Vec<(TransactionID, u64)>,
+    // deposits that are currently disputed
+    disputed: Vec<(TransactionID, u64)>,
+}
+
+// Generator produces a deterministic stream of transactions and keeps track of the balances
+// the engine is expected to end up with after processing them
+pub struct Generator {
+    config: Config,
+    rng: Rng,
+    clients: Vec<ClientState>,
+    row: u64,
+}
+
+impl Generator {
+    pub fn new(config: Config) -> Self {
+        let clients = (1..=config.clients)
+            .map(|id| ClientState {
+                account: Account::new(id),
+                ..Default::default()
+            })
+            .collect();
+        Self {
+            rng: Rng(config.seed),
+            config,
+            clients,
+            row: 0,
+        }
+    }
+
+    // expected returns the expected final state of all accounts that were touched so far
+    pub fn expected(&self) -> Vec<Account> {
+        self.clients
+            .iter()
+            .filter(|c| c.touched)
+            .map(|c| c.account.clone())
+            .collect()
+    }
+
+    fn pick_client(&mut self) -> usize {
+        // prefer clients that are not locked, so most transactions have an effect
+        let mut idx = self.rng.below(self.clients.len() as u64) as usize;
+        for _ in 0..4 {
+            if !self.clients[idx].account.locked {
+                break;
+            }
+            idx = self.rng.below(self.clients.len() as u64) as usize;
+        }
+        idx
+    }
+
+    fn pick_type(&mut self) -> TxType {
+        let mix = &self.config.mix;
+        let weights = [
+            (TxType::Deposit, mix.deposit),
+            (TxType::Withdrawal, mix.withdrawal),
+            (TxType::Dispute, mix.dispute),
+            (TxType::Resolve, mix.resolve),
+            (TxType::Chargeback, mix.chargeback),
+        ];
+        let sum: u64 = weights.iter().map(|(_, w)| *w as u64).sum();
+        let mut n = self.rng.below(sum.max(1));
+        for (type_, weight) in weights {
+            if n < weight as u64 {
+                return type_;
+            }
+            n -= weight as u64;
+        }
+        TxType::Deposit
+    }
+
+    // invalid produces a transaction that the engine has to reject without changing any balance
+    fn invalid(&mut self, idx: usize, tx: TransactionID) -> Transaction {
+        self.clients[idx].touched = true;
+        let account = &self.clients[idx].account;
+        // the generator never lets balances go negative
+        let (id, available, locked) = (account.id, account.available as u64, account.locked);
+        if locked {
+            let amount = self.rng.between(1, self.config.max_amount);
+            return new_tx(TxType::Deposit, id, tx, Some(amount));
+        }
+        match self.rng.below(2) {
+            // withdraw more than available
+            0 => {
+                let amount = available + self.rng.between(1, self.config.max_amount);
+                new_tx(TxType::Withdrawal, id, tx, Some(amount))
+            }
+            // reference a transaction that never exists
+            _ => {
+                let type_ = [TxType::Dispute, TxType::Resolve, TxType::Chargeback][self.rng.below(3) as usize].clone();
+                new_tx(type_, id, TransactionID::MAX - self.rng.below(1000) as TransactionID, None)
+            }
+        }
+    }
+
+    fn valid(&mut self, idx: usize, tx: TransactionID) -> Transaction {
+        let type_ = self.pick_type();
+        let pick_open = self.rng.below(self.clients[idx].open.len().max(1) as u64) as usize;
+        let pick_disputed = self.rng.below(self.clients[idx].disputed.len().max(1) as u64) as usize;
+
+        // fall back to a deposit if the picked type would not make sense for this client
+        let client = &self.clients[idx];
+        let available = client.account.available as u64;
+        let type_ = match type_ {
+            TxType::Withdrawal if available == 0 => TxType::Deposit,
+            // a dispute on a deposit whose funds are already gone would only hold back part of it
+            TxType::Dispute if client.open.get(pick_open).is_none_or(|(_, amount)| *amount > available) => {
+                TxType::Deposit
+            }
+            TxType::Resolve | TxType::Chargeback if client.disputed.is_empty() => TxType::Deposit,
+            type_ => type_,
+        };
+
+        let amount = match type_ {
+            TxType::Deposit => self.rng.between(1, self.config.max_amount),
+            TxType::Withdrawal => self.rng.between(1, available),
+            _ => 0,
+        };
+
+        let client = &mut self.clients[idx];
+        client.touched = true;
+        let account = &mut client.account;
+        let signed = amount as i128;
+        match type_ {
+            TxType::Deposit => {
+                account.available += signed;
+                account.total += signed;
+                client.open.push((tx, amount));
+                new_tx(TxType::Deposit, account.id, tx, Some(amount))
+            }
+            TxType::Withdrawal => {
+                account.available -= signed;
+                account.total -= signed;
+                new_tx(TxType::Withdrawal, account.id, tx, Some(amount))
+            }
+            TxType::Dispute => {
+                let (source, amount) = client.open.swap_remove(pick_open);
+                client.disputed.push((source, amount));
+                account.available -= amount as i128;
+                account.held += amount as i128;
+                new_tx(TxType::Dispute, account.id, source, None)
+            }
+            TxType::Resolve => {
+                let (source, amount) = client.disputed.swap_remove(pick_disputed);
+                account.held -= amount as i128;
+                account.available += amount as i128;
+                new_tx(TxType::Resolve, account.id, source, None)
+            }
+            TxType::Chargeback => {
+                let (source, amount) = client.disputed.swap_remove(pick_disputed);
+                account.held -= amount as i128;
+                account.total -= amount as i128;
+                account.locked = true;
+                new_tx(TxType::Chargeback, account.id, source, None)
+            }
+            // the mix only contains transactions of clients
+            type_ => unreachable!("{:?} is never picked", type_),
+        }
+    }
+}
+
+impl Iterator for Generator {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Transaction> {
+        if self.row >= self.config.rows || self.clients.is_empty() {
+            return None;
+        }
+        self.row += 1;
+        // deposits and withdrawals use the row number as their transaction id
+        let tx = self.row as TransactionID;
+        let idx = self.pick_client();
+        if self.clients[idx].account.locked || self.rng.chance(self.config.invalid_rate) {
+            Some(self.invalid(idx, tx))
+        } else {
+            Some(self.valid(idx, tx))
+        }
+    }
+}
+
+fn new_tx(type_: TxType, client: ClientID, tx: TransactionID, amount: Option<u64>) -> Transaction {
+    Transaction {
+        type_,
+        client,
+        tx,
+        amount: amount.map(u128::from),
+        currency: Currency::DEFAULT,
+        destination: None,
+        fee: 0,
+        charged_back: 0,
+        timestamp: None,
+    }
+}
+
+// format_row formats the transaction the same way as the input files in `tests/`, with all decimal places of the currency
+fn format_row(tx: &Transaction) -> String {
+    let type_ = tx.type_.as_str();
+    let assets = Assets::default();
+    let (scale, precision) = (assets.scale(tx.currency), assets.precision(tx.currency) as usize);
+    match tx.amount {
+        Some(amount) => format!(
+            "{}, {}, {}, {}.{:0width$}\n",
+            type_,
+            tx.client,
+            tx.tx,
+            amount / scale,
+            amount % scale,
+            width = precision
+        ),
+        None => format!("{}, {}, {},\n", type_, tx.client, tx.tx),
+    }
+}
+
+// write_transactions writes all transactions of the generator as input csv
+pub async fn write_transactions<W: AsyncWrite + Unpin>(generator: &mut Generator, writer: W) -> Result<()> {
+    let mut writer = BufWriter::new(writer);
+    writer.write_all(b"type, client, tx, amount\n").await?;
+    for tx in generator {
+        writer.write_all(format_row(&tx).as_bytes()).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+// write_expected writes the expected final account states sorted by client id and currency
+pub async fn write_expected<W: AsyncWrite + Unpin>(generator: &Generator, writer: W) -> Result<()> {
+    let mut writer = AsyncSerializer::from_writer(writer);
+    let accounts: BTreeMap<AccountKey, Account> = generator.expected().into_iter().map(|a| (a.key(), a)).collect();
+    for row in Assets::default().account_rows(accounts.into_values()) {
+        writer.serialize(row).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        accounts::Manager,
+        storage::{InMemoryKVStore, KVStore},
+        verify::VerifyMode,
+    };
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    #[test]
+    fn test_generator_is_deterministic() {
+        let config = Config {
+            seed: 42,
+            rows: 1000,
+            ..Default::default()
+        };
+        let a: Vec<String> = Generator::new(config.clone()).map(|tx| format_row(&tx)).collect();
+        let b: Vec<String> = Generator::new(config.clone()).map(|tx| format_row(&tx)).collect();
+        let c: Vec<String> = Generator::new(Config { seed: 43, ..config }).map(|tx| format_row(&tx)).collect();
+        assert_eq!(a.len(), 1000);
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[tokio::test]
+    async fn test_generator_expectations_match_engine() -> Result<()> {
+        let account_store = Arc::new(Mutex::new(InMemoryKVStore::<AccountKey, Account>::new()?));
+        let tx_store = Arc::new(Mutex::new(
+            InMemoryKVStore::<TransactionID, Transaction>::new()?,
+        ));
+        let mut mgr = Manager::new(account_store.clone(), tx_store).with_verify_mode(VerifyMode::EachTransaction);
+
+        let mut generator = Generator::new(Config {
+            seed: 7,
+            clients: 20,
+            rows: 5000,
+            invalid_rate: 0.1,
+            ..Default::default()
+        });
+        let mut rejected = 0;
+        for tx in &mut generator {
+            if mgr.process_transaction(tx).await.is_err() {
+                rejected += 1;
+            }
+        }
+        assert!(rejected > 0);
+
+        let store = account_store.lock().await;
+        let expected = generator.expected();
+        assert_eq!(expected.len(), 20);
+        for account in expected {
+            let actual = store.get(account.key())?;
+            assert_eq!(actual.available, account.available);
+            assert_eq!(actual.held, account.held);
+            assert_eq!(actual.total, account.total);
+            assert_eq!(actual.locked, account.locked);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,724 @@
+use csv_async::{AsyncReaderBuilder, AsyncSerializer};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+
+use crate::{
+    accounts::Manager,
+    assets::Assets,
+    fees::FeeSchedule,
+    error::{Error, Result},
+    limits::{CountedWithdrawal, Limits},
+    metrics::APPLIED,
+    policy::Policy,
+    storage::{InMemoryKVStore, KVStore},
+    types::{Account, AccountKey, Admin, ChargedBack, ClientID, Currency, Hold, Timestamp, Transaction, TransactionID, TxType},
+};
+
+// Every processed input row gets a sequence number, starting at 1 for the first row
+pub type SequenceNumber = u64;
+
+// A snapshot holds the state of all accounts right after the event with the given sequence number was applied,
+// together with the holds that were open at that point, the withdrawals that counted against the limits, the parts
+// of transactions that were charged back without a hold and the clock of the engine
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub seq: SequenceNumber,
+    pub accounts: HashMap<AccountKey, Account>,
+    pub holds: Vec<Hold>,
+    pub withdrawals: Vec<CountedWithdrawal>,
+    pub chargebacks: Vec<ChargedBack>,
+    pub clock: Option<Timestamp>,
+}
+
+// This is one row of the clocks of the snapshots as seen in the persisted journal
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct ClockRow {
+    seq: SequenceNumber,
+    clock: Timestamp,
+}
+
+// This is one event row as seen in the persisted journal
+// The amount is kept in its internal representation, so replaying a journal is lossless
+// Transfers additionally record their destination, admin operations the operator that performed them and the reason
+// and deposits and withdrawals the fee that was charged on them
+// The result is `applied` or the kind of error the transaction was rejected with
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct EventRow {
+    seq: SequenceNumber,
+    #[serde(rename = "type")]
+    type_: TxType,
+    client: ClientID,
+    tx: TransactionID,
+    amount: Option<u128>,
+    currency: Currency,
+    destination: Option<ClientID>,
+    fee: u128,
+    operator: Option<String>,
+    reason: Option<String>,
+    // the column is empty for transactions without a time, as long as the input had no timestamps
+    timestamp: Option<Timestamp>,
+    result: String,
+}
+
+// History keeps the ordered log of all processed transactions together with periodic snapshots of the accounts.
+// It can reconstruct the state of an account as of any sequence number by replaying the events
+// since the closest snapshot, so the replay cost is bounded by the snapshot interval.
+#[derive(Debug)]
+pub struct History<E, S, I>
+where
+    E: KVStore<Key = SequenceNumber, Value = Transaction>,
+    S: KVStore<Key = SequenceNumber, Value = Snapshot>,
+    I: KVStore<Key = TransactionID, Value = SequenceNumber>,
+{
+    events: E,
+    snapshots: S,
+    // index maps the id of a deposit, withdrawal or transfer to the sequence number it was recorded at
+    index: I,
+    // the sequence numbers of the events that were rejected, a replay skips them instead of processing them again
+    rejected: HashSet<SequenceNumber>,
+    snapshot_seqs: Vec<SequenceNumber>,
+    len: SequenceNumber,
+    // the policy the events were processed with, it's needed to replay them
+    policy: Policy,
+    // the assets registry of the run, it's needed to present the balances
+    assets: Assets,
+    // the fee schedule the events were processed with, it's needed to replay them
+    fees: FeeSchedule,
+    // the limits on withdrawals the events were processed with, they are needed to replay them
+    limits: Limits,
+}
+
+pub type InMemoryHistory = History<
+    InMemoryKVStore<SequenceNumber, Transaction>,
+    InMemoryKVStore<SequenceNumber, Snapshot>,
+    InMemoryKVStore<TransactionID, SequenceNumber>,
+>;
+
+impl<E, S, I> History<E, S, I>
+where
+    E: KVStore<Key = SequenceNumber, Value = Transaction>,
+    S: KVStore<Key = SequenceNumber, Value = Snapshot>,
+    I: KVStore<Key = TransactionID, Value = SequenceNumber>,
+{
+    pub fn new(events: E, snapshots: S, index: I) -> Self {
+        Self {
+            events,
+            snapshots,
+            index,
+            rejected: HashSet::new(),
+            snapshot_seqs: Vec::new(),
+            len: 0,
+            policy: Policy::default(),
+            assets: Assets::default(),
+            fees: FeeSchedule::default(),
+            limits: Limits::default(),
+        }
+    }
+
+    // with_policy sets the policy used to replay the events
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // with_assets sets the assets registry the events were processed with
+    pub fn with_assets(mut self, assets: Assets) -> Self {
+        self.assets = assets;
+        self
+    }
+
+    pub fn assets(&self) -> &Assets {
+        &self.assets
+    }
+
+    // with_fees sets the fee schedule used to replay the events
+    pub fn with_fees(mut self, fees: FeeSchedule) -> Self {
+        self.fees = fees;
+        self
+    }
+
+    // with_limits sets the limits on withdrawals used to replay the events
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // record appends the transaction to the history and returns its sequence number
+    pub fn record(&mut self, tx: Transaction) -> Result<SequenceNumber> {
+        let seq = self.len + 1;
+        if tx.type_.is_disputable() {
+            self.index.set(tx.tx, seq)?;
+        }
+        self.events.set(seq, tx)?;
+        self.len = seq;
+        Ok(seq)
+    }
+
+    // record_rejected appends a transaction that was rejected to the history and returns its sequence number,
+    // like the manager it keeps a rejected deposit or withdrawal but not a rejected transfer
+    pub fn record_rejected(&mut self, tx: Transaction) -> Result<SequenceNumber> {
+        let seq = self.len + 1;
+        if tx.type_.is_disputable() && tx.type_ != TxType::Transfer {
+            self.index.set(tx.tx, seq)?;
+        }
+        self.events.set(seq, tx)?;
+        self.rejected.insert(seq);
+        self.len = seq;
+        Ok(seq)
+    }
+
+    // add_snapshot stores the given snapshot, snapshots must be added in order
+    pub fn add_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+        if snapshot.seq > self.len || self.snapshot_seqs.last() >= Some(&snapshot.seq) {
+            return Err(Error::InvalidArguments);
+        }
+        self.snapshot_seqs.push(snapshot.seq);
+        self.snapshots.set(snapshot.seq, snapshot)
+    }
+
+    // seq_of returns the sequence number at which the given deposit, withdrawal or transfer was recorded
+    pub fn seq_of(&self, tx: TransactionID) -> Result<SequenceNumber> {
+        self.index.get(tx).copied()
+    }
+
+    // accounts_at reconstructs the accounts of the given client in all currencies as they were
+    // right after the event with the given sequence number, sorted by currency
+    pub async fn accounts_at(&self, client: ClientID, seq: SequenceNumber) -> Result<Vec<Account>> {
+        if seq > self.len {
+            return Err(Error::NotFound);
+        }
+
+        // start from the closest snapshot at or before the requested point in time
+        let account_store = Arc::new(Mutex::new(InMemoryKVStore::<AccountKey, Account>::new()?));
+        let tx_store = Arc::new(Mutex::new(
+            InMemoryKVStore::<TransactionID, Transaction>::new()?,
+        ));
+        let mut start = 0;
+        let mut holds = Vec::new();
+        let mut withdrawals = Vec::new();
+        let mut chargebacks = Vec::new();
+        let mut clock = None;
+        let idx = self.snapshot_seqs.partition_point(|s| *s <= seq);
+        if idx > 0 {
+            let snapshot = self.snapshots.get(self.snapshot_seqs[idx - 1])?;
+            for account in snapshot.accounts.values() {
+                account_store.lock().await.set(account.key(), account.clone())?;
+            }
+            start = snapshot.seq;
+            holds = snapshot.holds.clone();
+            withdrawals = snapshot.withdrawals.clone();
+            chargebacks = snapshot.chargebacks.clone();
+            clock = snapshot.clock;
+        }
+        // the transactions of the open holds are visible as well, a dispute past its deadline needs to know what it disputes
+        for hold in &holds {
+            if let Ok(source_seq) = self.index.get(hold.tx) {
+                tx_store.lock().await.set(hold.tx, self.events.get(*source_seq)?.clone())?;
+            }
+        }
+        // and so are the transactions that were charged back in part, their fee was refunded for that part already
+        for chargeback in &chargebacks {
+            if let Ok(source_seq) = self.index.get(chargeback.tx) {
+                let tx = Transaction {
+                    charged_back: chargeback.amount,
+                    ..self.events.get(*source_seq)?.clone()
+                };
+                tx_store.lock().await.set(chargeback.tx, tx)?;
+            }
+        }
+
+        // replay all events since the snapshot, not only the ones of this client:
+        // whether a transfer is accepted depends on the accounts of both clients
+        // the manager stores the transactions replayed since the snapshot itself, the ones referenced by disputes
+        // that were recorded before it are looked up in the history, unless the replay knows them already
+        let mut manager = Manager::new(account_store.clone(), tx_store.clone())
+            .with_policy(self.policy)
+            .with_fees(self.fees.clone())
+            .with_limits(self.limits.clone())
+            .with_assets(self.assets.clone())
+            .with_sequence(start)
+            .with_clock(clock)
+            .with_holds(holds)
+            .with_withdrawals(withdrawals);
+        for s in start + 1..=seq {
+            let tx = self.events.get(s)?;
+            if !tx.type_.is_disputable() {
+                if let Ok(source_seq) = self.index.get(tx.tx) {
+                    let mut tx_store = tx_store.lock().await;
+                    if *source_seq <= start && tx_store.get(tx.tx).is_err() {
+                        tx_store.set(tx.tx, self.events.get(*source_seq)?.clone())?;
+                    }
+                }
+            }
+            // a transaction that was rejected is skipped, whatever rejected it, e.g. a validator that isn't part of the
+            // replay, the others are processed again with the rules of the run, which reject what they rejected then
+            if self.rejected.contains(&s) {
+                manager.skip_transaction(tx.clone()).await?;
+            } else {
+                let _ = manager.process_transaction(tx.clone()).await;
+            }
+        }
+
+        let mut accounts: Vec<Account> = account_store
+            .lock()
+            .await
+            .clone()
+            .into_iter()
+            .map(|(_, a)| a)
+            .filter(|a| a.id == client)
+            .collect();
+        if accounts.is_empty() {
+            return Err(Error::NotFound);
+        }
+        accounts.sort_by_key(|a| a.currency);
+        Ok(accounts)
+    }
+}
+
+// Journal persists the history of a run into a directory, so it can be queried later on.
+// The events are written to `events.csv`, every snapshot is written to `snapshot-<seq>.csv`, its open holds
+// to `holds-<seq>.csv`, the withdrawals that counted against the limits to `withdrawals-<seq>.csv` and the parts of
+// transactions charged back without a hold to `chargebacks-<seq>.csv`, the clocks of the snapshots are collected in
+// `clocks.csv`. The policy, the assets registry, the fee schedule and the limits
+// of the run are written to `policy.conf`, `assets.conf`, `fees.conf` and `limits.conf`.
+pub struct Journal {
+    dir: PathBuf,
+    events: AsyncSerializer<File>,
+    clocks: AsyncSerializer<File>,
+    snapshot_interval: SequenceNumber,
+    seq: SequenceNumber,
+}
+
+impl Journal {
+    pub async fn create(
+        dir: &Path,
+        snapshot_interval: SequenceNumber,
+        policy: &Policy,
+        assets: &Assets,
+        fees: &FeeSchedule,
+        limits: &Limits,
+    ) -> Result<Self> {
+        tokio::fs::create_dir_all(dir).await?;
+        tokio::fs::write(dir.join("policy.conf"), policy.to_string()).await?;
+        tokio::fs::write(dir.join("assets.conf"), assets.to_string()).await?;
+        tokio::fs::write(dir.join("fees.conf"), fees.to_string()).await?;
+        tokio::fs::write(dir.join("limits.conf"), limits.to_string()).await?;
+        let file = File::create(dir.join("events.csv")).await?;
+        let clocks = File::create(dir.join("clocks.csv")).await?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            events: AsyncSerializer::from_writer(file),
+            clocks: AsyncSerializer::from_writer(clocks),
+            snapshot_interval,
+            seq: 0,
+        })
+    }
+
+    // record appends the transaction to the journal together with its result and returns its sequence number
+    pub async fn record(&mut self, tx: &Transaction, admin: Option<&Admin>, result: &str) -> Result<SequenceNumber> {
+        self.seq += 1;
+        let row = EventRow {
+            seq: self.seq,
+            type_: tx.type_.clone(),
+            client: tx.client,
+            tx: tx.tx,
+            amount: tx.amount,
+            currency: tx.currency,
+            destination: tx.destination,
+            fee: tx.fee,
+            operator: admin.map(|a| a.operator.clone()),
+            reason: admin.map(|a| a.reason.clone()),
+            timestamp: tx.timestamp,
+            result: result.to_string(),
+        };
+        self.events.serialize(row).await?;
+        Ok(self.seq)
+    }
+
+    // snapshot_due returns true if a snapshot should be taken after the last recorded event
+    pub fn snapshot_due(&self) -> bool {
+        self.snapshot_interval > 0 && self.seq.is_multiple_of(self.snapshot_interval)
+    }
+
+    // write_snapshot persists the given accounts, open holds, counted withdrawals, charged back parts and clock as the
+    // snapshot for the last recorded event
+    pub async fn write_snapshot<A, H, W, C>(
+        &mut self,
+        accounts: A,
+        holds: H,
+        withdrawals: W,
+        chargebacks: C,
+        clock: Option<Timestamp>,
+    ) -> Result<()>
+    where
+        A: IntoIterator<Item = Account>,
+        H: IntoIterator<Item = Hold>,
+        W: IntoIterator<Item = CountedWithdrawal>,
+        C: IntoIterator<Item = ChargedBack>,
+    {
+        let path = self.dir.join(format!("snapshot-{}.csv", self.seq));
+        let mut writer = AsyncSerializer::from_writer(File::create(path).await?);
+        for account in accounts {
+            writer.serialize(account).await?;
+        }
+        writer.flush().await?;
+        let path = self.dir.join(format!("holds-{}.csv", self.seq));
+        let mut writer = AsyncSerializer::from_writer(File::create(path).await?);
+        for hold in holds {
+            writer.serialize(hold).await?;
+        }
+        writer.flush().await?;
+        let path = self.dir.join(format!("withdrawals-{}.csv", self.seq));
+        let mut writer = AsyncSerializer::from_writer(File::create(path).await?);
+        for withdrawal in withdrawals {
+            writer.serialize(withdrawal).await?;
+        }
+        writer.flush().await?;
+        let path = self.dir.join(format!("chargebacks-{}.csv", self.seq));
+        let mut writer = AsyncSerializer::from_writer(File::create(path).await?);
+        for chargeback in chargebacks {
+            writer.serialize(chargeback).await?;
+        }
+        writer.flush().await?;
+        // as long as the input had no timestamps there is no clock
+        if let Some(clock) = clock {
+            self.clocks.serialize(ClockRow { seq: self.seq, clock }).await?;
+            self.clocks.flush().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> Result<()> {
+        self.events.flush().await?;
+        Ok(())
+    }
+}
+
+// load reads a journal written by `Journal` back into a queryable history
+pub async fn load(dir: &Path) -> Result<InMemoryHistory> {
+    let policy = Policy::load(&dir.join("policy.conf")).await?;
+    let assets = Assets::load(&dir.join("assets.conf")).await?;
+    let fees = FeeSchedule::load(&dir.join("fees.conf")).await?;
+    let limits = Limits::load(&dir.join("limits.conf")).await?;
+    let mut history = History::new(
+        InMemoryKVStore::new()?,
+        InMemoryKVStore::new()?,
+        InMemoryKVStore::new()?,
+    )
+    .with_policy(policy)
+    .with_assets(assets)
+    .with_fees(fees)
+    .with_limits(limits);
+
+    let file = File::open(dir.join("events.csv")).await?;
+    let mut reader = AsyncReaderBuilder::new().create_deserializer(file);
+    let mut rows = reader.deserialize::<EventRow>();
+    while let Some(row) = rows.next().await {
+        let row = row?;
+        let tx = Transaction {
+            type_: row.type_,
+            client: row.client,
+            tx: row.tx,
+            amount: row.amount,
+            currency: row.currency,
+            destination: row.destination,
+            fee: row.fee,
+            charged_back: 0,
+            timestamp: row.timestamp,
+        };
+        let seq = match row.result.as_str() {
+            APPLIED => history.record(tx)?,
+            _ => history.record_rejected(tx)?,
+        };
+        if seq != row.seq {
+            return Err(Error::InvalidArguments);
+        }
+    }
+
+    let mut clocks = HashMap::new();
+    let file = File::open(dir.join("clocks.csv")).await?;
+    let mut reader = AsyncReaderBuilder::new().create_deserializer(file);
+    let mut rows = reader.deserialize::<ClockRow>();
+    while let Some(row) = rows.next().await {
+        let row = row?;
+        clocks.insert(row.seq, row.clock);
+    }
+
+    let mut snapshot_seqs = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let seq = name
+            .to_str()
+            .and_then(|n| n.strip_prefix("snapshot-"))
+            .and_then(|n| n.strip_suffix(".csv"))
+            .and_then(|n| n.parse::<SequenceNumber>().ok());
+        if let Some(seq) = seq {
+            snapshot_seqs.push(seq);
+        }
+    }
+    snapshot_seqs.sort_unstable();
+
+    for seq in snapshot_seqs {
+        let file = File::open(dir.join(format!("snapshot-{}.csv", seq))).await?;
+        let mut reader = AsyncReaderBuilder::new().create_deserializer(file);
+        let mut rows = reader.deserialize::<Account>();
+        let mut snapshot = Snapshot {
+            seq,
+            clock: clocks.get(&seq).copied(),
+            ..Default::default()
+        };
+        while let Some(account) = rows.next().await {
+            let account = account?;
+            snapshot.accounts.insert(account.key(), account);
+        }
+        let file = File::open(dir.join(format!("holds-{}.csv", seq))).await?;
+        let mut reader = AsyncReaderBuilder::new().create_deserializer(file);
+        let mut rows = reader.deserialize::<Hold>();
+        while let Some(hold) = rows.next().await {
+            snapshot.holds.push(hold?);
+        }
+        let file = File::open(dir.join(format!("withdrawals-{}.csv", seq))).await?;
+        let mut reader = AsyncReaderBuilder::new().create_deserializer(file);
+        let mut rows = reader.deserialize::<CountedWithdrawal>();
+        while let Some(withdrawal) = rows.next().await {
+            snapshot.withdrawals.push(withdrawal?);
+        }
+        let file = File::open(dir.join(format!("chargebacks-{}.csv", seq))).await?;
+        let mut reader = AsyncReaderBuilder::new().create_deserializer(file);
+        let mut rows = reader.deserialize::<ChargedBack>();
+        while let Some(chargeback) = rows.next().await {
+            snapshot.chargebacks.push(chargeback?);
+        }
+        history.add_snapshot(snapshot)?;
+    }
+
+    Ok(history)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::HoldReason;
+
+    fn tx(type_: TxType, client: ClientID, tx: TransactionID, amount: Option<u128>) -> Transaction {
+        Transaction {
+            type_,
+            client,
+            tx,
+            amount,
+            ..Default::default()
+        }
+    }
+
+    fn new_history() -> Result<InMemoryHistory> {
+        Ok(History::new(
+            InMemoryKVStore::new()?,
+            InMemoryKVStore::new()?,
+            InMemoryKVStore::new()?,
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_account_at_replays_events() -> Result<()> {
+        let mut history = new_history()?;
+        history.record(tx(TxType::Deposit, 1, 1, Some(100)))?;
+        history.record(tx(TxType::Deposit, 2, 2, Some(70)))?;
+        history.record(tx(TxType::Withdrawal, 1, 3, Some(30)))?;
+        history.record(tx(TxType::Dispute, 1, 1, None))?;
+
+        let account = &history.accounts_at(1, 1).await?[0];
+        assert_eq!(account.available, 100);
+        assert_eq!(account.held, 0);
+
+        let account = &history.accounts_at(1, 3).await?[0];
+        assert_eq!(account.available, 70);
+        assert_eq!(account.total, 70);
+
+        let account = &history.accounts_at(1, 4).await?[0];
+        assert_eq!(account.available, 0);
+        assert_eq!(account.held, 70);
+        assert_eq!(account.total, 70);
+
+        assert!(history.accounts_at(2, 1).await.is_err());
+        assert!(history.accounts_at(1, 5).await.is_err());
+        assert_eq!(history.seq_of(3)?, 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_account_at_starts_from_snapshot() -> Result<()> {
+        let mut history = new_history()?;
+        history.record(tx(TxType::Deposit, 1, 1, Some(100)))?;
+        history.record(tx(TxType::Deposit, 1, 2, Some(50)))?;
+
+        // the snapshot deliberately differs from what the events would produce, to prove it is used
+        let mut snapshot = Snapshot {
+            seq: 2,
+            ..Default::default()
+        };
+        let mut account = Account::new(1);
+        account.available = 1000;
+        account.total = 1000;
+        snapshot.accounts.insert(account.key(), account);
+        history.add_snapshot(snapshot)?;
+
+        history.record(tx(TxType::Withdrawal, 1, 3, Some(10)))?;
+        history.record(tx(TxType::Dispute, 1, 5, None))?;
+        history.record(tx(TxType::Deposit, 1, 5, Some(20)))?;
+
+        let account = &history.accounts_at(1, 1).await?[0];
+        assert_eq!(account.available, 100);
+
+        let account = &history.accounts_at(1, 3).await?[0];
+        assert_eq!(account.available, 990);
+
+        // a dispute on a transaction that only shows up later is not applied
+        let account = &history.accounts_at(1, 5).await?[0];
+        assert_eq!(account.available, 1010);
+        assert_eq!(account.held, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accounts_at_replays_transfers() -> Result<()> {
+        let mut history = new_history()?;
+        history.record(tx(TxType::Deposit, 1, 1, Some(100)))?;
+        history.record(Transaction {
+            destination: Some(2),
+            ..tx(TxType::Transfer, 1, 2, Some(40))
+        })?;
+        history.record(tx(TxType::Withdrawal, 2, 3, Some(10)))?;
+
+        // the transfer is recorded as an event of the source, but it's part of the history of the destination as well
+        let account = &history.accounts_at(2, 3).await?[0];
+        assert_eq!(account.available, 30);
+        let account = &history.accounts_at(1, 3).await?[0];
+        assert_eq!(account.available, 60);
+        assert!(history.accounts_at(2, 1).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accounts_at_restores_holds() -> Result<()> {
+        let mut history = new_history()?;
+        history.record(tx(TxType::Deposit, 1, 1, Some(100)))?;
+        history.record(tx(TxType::Authorize, 1, 2, Some(60)))?;
+        let mut account = Account::new(1);
+        account.available = 40;
+        account.held = 60;
+        account.held_authorization = 60;
+        account.total = 100;
+        history.add_snapshot(Snapshot {
+            seq: 2,
+            accounts: HashMap::from([(account.key(), account)]),
+            holds: vec![Hold {
+                tx: 2,
+                reason: HoldReason::Authorization,
+                client: 1,
+                currency: Currency::DEFAULT,
+                amount: 60,
+                expires: None,
+                disputed: 0,
+                charged_back: 0,
+                deadline: None,
+            }],
+            ..Default::default()
+        })?;
+        history.record(tx(TxType::Capture, 1, 2, None))?;
+
+        // the authorization was opened before the snapshot, it can only be captured if the snapshot restores it
+        let account = &history.accounts_at(1, 3).await?[0];
+        assert_eq!((account.available, account.held_authorization, account.total), (40, 0, 40));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accounts_at_applies_limits() -> Result<()> {
+        let mut history = new_history()?.with_limits("default.daily_count = 1".parse()?);
+        history.record(tx(TxType::Deposit, 1, 1, Some(100)))?;
+        history.record(tx(TxType::Withdrawal, 1, 2, Some(10)))?;
+        let mut account = Account::new(1);
+        account.available = 90;
+        account.total = 90;
+        history.add_snapshot(Snapshot {
+            seq: 2,
+            accounts: HashMap::from([(account.key(), account)]),
+            withdrawals: vec![CountedWithdrawal {
+                client: 1,
+                currency: Currency::DEFAULT,
+                timestamp: 0,
+                amount: 10,
+            }],
+            ..Default::default()
+        })?;
+        history.record(tx(TxType::Withdrawal, 1, 3, Some(10)))?;
+
+        // the second withdrawal of the day was rejected, whether the replay starts at the snapshot or not
+        let account = &history.accounts_at(1, 3).await?[0];
+        assert_eq!(account.available, 90);
+        history.snapshot_seqs.clear();
+        let account = &history.accounts_at(1, 3).await?[0];
+        assert_eq!(account.available, 90);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accounts_at_restores_chargebacks() -> Result<()> {
+        let fees = "house = 9\nrefund_on_chargeback = true\nwithdrawal = 1%".parse()?;
+        let mut history = new_history()?.with_fees(fees);
+        history.record(tx(TxType::Deposit, 1, 1, Some(1000)))?;
+        history.record(Transaction { fee: 1, ..tx(TxType::Withdrawal, 1, 2, Some(100)) })?;
+        history.record(tx(TxType::Chargeback, 1, 2, None))?;
+        let mut account = Account::new(1);
+        account.available = 1000;
+        account.total = 1000;
+        history.add_snapshot(Snapshot {
+            seq: 3,
+            accounts: HashMap::from([(account.key(), account), ((9, Currency::DEFAULT), Account::new(9))]),
+            chargebacks: vec![ChargedBack { tx: 2, amount: 100 }],
+            ..Default::default()
+        })?;
+        history.record(tx(TxType::Chargeback, 1, 2, None))?;
+
+        // the fee of the withdrawal was refunded by the first chargeback, whether the replay starts at the snapshot or not
+        let account = &history.accounts_at(1, 4).await?[0];
+        assert_eq!(account.available, 1100);
+        history.snapshot_seqs.clear();
+        let account = &history.accounts_at(1, 4).await?[0];
+        assert_eq!(account.available, 1100);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accounts_at_skips_rejected_events() -> Result<()> {
+        let mut history = new_history()?;
+        history.record(tx(TxType::Deposit, 1, 1, Some(100)))?;
+        // e.g. rejected by a validator of the run, the replay has none that would reject it again
+        history.record_rejected(tx(TxType::Withdrawal, 1, 2, Some(30)))?;
+        history.record_rejected(Transaction {
+            destination: Some(2),
+            ..tx(TxType::Transfer, 1, 3, Some(40))
+        })?;
+        history.record(tx(TxType::Dispute, 1, 3, None))?;
+
+        let account = &history.accounts_at(1, 4).await?[0];
+        assert_eq!((account.available, account.held), (100, 0));
+        // the rejected transfer looked up the account of its destination, like it did in the run, but moved nothing
+        assert_eq!(history.accounts_at(2, 4).await?[0].total, 0);
+        assert!(history.seq_of(3).is_err());
+
+        Ok(())
+    }
+}
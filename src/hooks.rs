@@ -0,0 +1,102 @@
+use std::fmt;
+
+use crate::{
+    error::{Error, Result},
+    types::{Account, Transaction},
+};
+
+// Verdict is what a validator decides about a transaction before it's applied
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Accept,
+    // the transaction is rejected with `Rejected` and the reason
+    Reject(String),
+    // the transaction is applied, but the reason is reported with it, e.g. for a manual review
+    Flag(String),
+}
+
+// Validator checks a transaction against the account it's applied to before the business rules of the engine,
+// e.g. against a sanctions list or the rules of a partner
+// The account is the one of the client of the transaction in the currency the transaction is applied in.
+pub trait Validator: Send {
+    fn validate(&mut self, tx: &Transaction, account: &Account) -> Verdict;
+}
+
+// Observer is told about every account a transaction changed, once the transaction is applied, e.g. to feed
+// anomaly heuristics
+// Besides the account of the client this includes the house account of a fee, both accounts of a transfer and
+// the accounts of expired holds and disputes resolved past their deadline.
+pub trait Observer: Send {
+    fn observe(&mut self, tx: &Transaction, before: &Account, after: &Account);
+}
+
+impl<F> Validator for F
+where
+    F: FnMut(&Transaction, &Account) -> Verdict + Send,
+{
+    fn validate(&mut self, tx: &Transaction, account: &Account) -> Verdict {
+        self(tx, account)
+    }
+}
+
+impl<F> Observer for F
+where
+    F: FnMut(&Transaction, &Account, &Account) + Send,
+{
+    fn observe(&mut self, tx: &Transaction, before: &Account, after: &Account) {
+        self(tx, before, after)
+    }
+}
+
+// Hooks holds the validators and observers of a manager, they are called in the order they were added
+#[derive(Default)]
+pub struct Hooks {
+    validators: Vec<Box<dyn Validator>>,
+    observers: Vec<Box<dyn Observer>>,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_validator<V: Validator + 'static>(mut self, validator: V) -> Self {
+        self.validators.push(Box::new(validator));
+        self
+    }
+
+    pub fn with_observer<O: Observer + 'static>(mut self, observer: O) -> Self {
+        self.observers.push(Box::new(observer));
+        self
+    }
+
+    // validate asks the validators about the transaction and returns the reasons it was flagged for,
+    // the first validator that rejects it has the last word
+    pub fn validate(&mut self, tx: &Transaction, account: &Account) -> Result<Vec<String>> {
+        let mut flags = Vec::new();
+        for validator in &mut self.validators {
+            match validator.validate(tx, account) {
+                Verdict::Accept => {}
+                Verdict::Reject(reason) => return Err(Error::Rejected(reason)),
+                Verdict::Flag(reason) => flags.push(reason),
+            }
+        }
+        Ok(flags)
+    }
+
+    pub fn observe(&mut self, tx: &Transaction, before: &Account, after: &Account) {
+        for observer in &mut self.observers {
+            observer.observe(tx, before, after);
+        }
+    }
+}
+
+// the hooks are opaque, so only their numbers are shown
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("validators", &self.validators.len())
+            .field("observers", &self.observers.len())
+            .finish()
+    }
+}
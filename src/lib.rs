@@ -0,0 +1,20 @@
+pub mod accounts;
+pub mod assets;
+pub mod cli;
+pub mod error;
+pub mod fees;
+pub mod generate;
+pub mod history;
+pub mod hooks;
+pub mod limits;
+pub mod logging;
+pub mod metrics;
+pub mod pipeline;
+pub mod policy;
+pub mod storage;
+pub mod summary;
+pub mod types;
+pub mod verify;
+
+#[cfg(test)]
+mod model_tests;
@@ -0,0 +1,277 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{
+    assets::Assets,
+    error::{Error, Result},
+    fees::Decimal,
+    types::{ClientID, Currency, Timestamp, SECONDS_PER_DAY},
+};
+
+// clients that aren't assigned to a tier are in this one
+pub const DEFAULT_TIER: &str = "default";
+
+// the longest window a limit is counted over, older withdrawals don't count against any limit
+pub const LONGEST_WINDOW: Timestamp = 7 * SECONDS_PER_DAY;
+
+// Limit names one of the limits of a tier
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Limit {
+    // the amount of a single withdrawal
+    Single,
+    // the amount and the number of the withdrawals within the last 24 hours
+    Daily,
+    DailyCount,
+    // the amount and the number of the withdrawals within the last 7 days
+    Weekly,
+    WeeklyCount,
+}
+
+impl Limit {
+    const ALL: [Limit; 5] = [Limit::Single, Limit::Daily, Limit::DailyCount, Limit::Weekly, Limit::WeeklyCount];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Single => "single",
+            Self::Daily => "daily",
+            Self::DailyCount => "daily_count",
+            Self::Weekly => "weekly",
+            Self::WeeklyCount => "weekly_count",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Limit> {
+        Self::ALL.into_iter().find(|limit| limit.as_str() == name)
+    }
+
+    // window returns how long a withdrawal counts against the limit, single withdrawals are on their own
+    fn window(&self) -> Timestamp {
+        match self {
+            Self::Single => 0,
+            Self::Daily | Self::DailyCount => SECONDS_PER_DAY,
+            Self::Weekly | Self::WeeklyCount => LONGEST_WINDOW,
+        }
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// CountedWithdrawal is an applied withdrawal or capture that counts against the limits of its account,
+// it's persisted with the snapshots of the history, so a replay counts it as well
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CountedWithdrawal {
+    pub client: ClientID,
+    pub currency: Currency,
+    pub timestamp: Timestamp,
+    pub amount: u128,
+}
+
+// Tier holds the limits of a group of clients, the limits it doesn't set don't apply
+#[derive(Debug, Clone, PartialEq, Default)]
+struct Tier {
+    amounts: BTreeMap<Limit, Decimal>,
+    counts: BTreeMap<Limit, u64>,
+}
+
+// Limits holds the risk limits on withdrawals per tier of clients
+//
+// A limits file has one `key = value` pair per line, empty lines and lines starting with `#` are ignored:
+//
+//   <tier>.<single|daily|weekly> = <amount>        the largest amount of a withdrawal, or withdrawn within 24 hours or 7 days
+//   <tier>.<daily_count|weekly_count> = <number>   the largest number of withdrawals within 24 hours or 7 days
+//   client <client id> = <tier>                    the tier of the client, by default it's `default`
+//
+// The amounts apply to every currency and are rounded to its precision. The windows are counted back from the
+// time of the withdrawal, withdrawals without a time count as happening at the same moment.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Limits {
+    tiers: BTreeMap<String, Tier>,
+    clients: BTreeMap<ClientID, String>,
+}
+
+impl Limits {
+    // load reads the limits from the given file
+    pub async fn load(path: &Path) -> Result<Self> {
+        tokio::fs::read_to_string(path).await?.parse()
+    }
+
+    fn tier(&self, client: ClientID) -> Option<&Tier> {
+        let name = self.clients.get(&client).map_or(DEFAULT_TIER, String::as_str);
+        self.tiers.get(name)
+    }
+
+    // counts_withdrawals returns true if the limits of the client depend on the withdrawals before, so they
+    // have to be kept for the length of the longest window
+    pub fn counts_withdrawals(&self, client: ClientID) -> bool {
+        self.tier(client)
+            .is_some_and(|tier| tier.amounts.keys().chain(tier.counts.keys()).any(|limit| limit.window() > 0))
+    }
+
+    // check returns `LimitExceeded` with the first limit the withdrawal of the amount at the given time breaks,
+    // given the earlier withdrawals of the account as pairs of their time and amount
+    pub fn check(
+        &self,
+        assets: &Assets,
+        client: ClientID,
+        currency: Currency,
+        amount: u128,
+        now: Timestamp,
+        withdrawals: &[(Timestamp, u128)],
+    ) -> Result<()> {
+        let tier = match self.tier(client) {
+            Some(tier) => tier,
+            None => return Ok(()),
+        };
+        let within = |limit: Limit| {
+            let window = limit.window();
+            withdrawals
+                .iter()
+                .filter(move |(time, _)| window > 0 && time.saturating_add(window) > now)
+        };
+        for limit in Limit::ALL {
+            let exceeded = match (tier.amounts.get(&limit), tier.counts.get(&limit)) {
+                (Some(max), _) => {
+                    let volume = within(limit).fold(amount, |sum, (_, amount)| sum.saturating_add(*amount));
+                    volume > max.units(assets.precision(currency))
+                }
+                (_, Some(max)) => within(limit).count() as u64 + 1 > *max,
+                _ => false,
+            };
+            if exceeded {
+                return Err(Error::LimitExceeded(limit));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Limits {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut limits = Limits::default();
+        for (idx, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: String| Error::InvalidLimits(format!("line {}: {}", idx + 1, reason));
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid("expected `key = value`".to_string()))?;
+            let (key, value) = (key.trim(), value.trim());
+            if let Some(client) = key.strip_prefix("client ") {
+                let client = client
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid(format!("invalid client id `{}`", client.trim())))?;
+                limits.clients.insert(client, tier_name(value).map_err(invalid)?);
+                continue;
+            }
+            let (tier, limit) = key
+                .split_once('.')
+                .and_then(|(tier, limit)| Some((tier, Limit::from_name(limit)?)))
+                .ok_or_else(|| invalid(format!("unknown key `{}`", key)))?;
+            let tier = limits.tiers.entry(tier_name(tier).map_err(invalid)?).or_default();
+            match limit {
+                Limit::DailyCount | Limit::WeeklyCount => {
+                    let count = value
+                        .parse()
+                        .map_err(|_| invalid(format!("expected a number of withdrawals, got `{}`", value)))?;
+                    tier.counts.insert(limit, count);
+                }
+                _ => {
+                    let amount = value
+                        .parse()
+                        .map_err(|_| invalid(format!("invalid amount `{}`", value)))?;
+                    tier.amounts.insert(limit, amount);
+                }
+            }
+        }
+        Ok(limits)
+    }
+}
+
+// tier_name checks that the name of a tier is a plain word
+fn tier_name(name: &str) -> core::result::Result<String, String> {
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-') {
+        return Err(format!("invalid tier `{}`", name));
+    }
+    Ok(name.to_string())
+}
+
+// the limits are written in the file format, so they can be persisted and loaded again
+impl fmt::Display for Limits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, tier) in &self.tiers {
+            for (limit, amount) in &tier.amounts {
+                writeln!(f, "{}.{} = {}", name, limit, amount)?;
+            }
+            for (limit, count) in &tier.counts {
+                writeln!(f, "{}.{} = {}", name, limit, count)?;
+            }
+        }
+        for (client, tier) in &self.clients {
+            writeln!(f, "client {} = {}", client, tier)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() -> Result<()> {
+        let limits: Limits = "\
+            # limits of the test\n\
+            default.single = 100\n\
+            default.daily = 150\n\
+            default.weekly_count = 3\n\
+            vip.single = 1000\n\
+            client 7 = vip\n\
+            client 8 = unlimited\n"
+            .parse()?;
+        assert_eq!(limits.to_string().parse::<Limits>()?, limits);
+        assert!(limits.counts_withdrawals(1));
+        assert!(!limits.counts_withdrawals(7));
+        assert!(!limits.counts_withdrawals(8));
+
+        let (assets, eur) = (Assets::default(), Currency::DEFAULT);
+        let now = 10 * SECONDS_PER_DAY;
+        let check = |client, amount, withdrawals: &[(Timestamp, u128)]| limits.check(&assets, client, eur, amount, now, withdrawals);
+        let exceeded = |res: Result<()>| match res {
+            Err(Error::LimitExceeded(limit)) => Some(limit),
+            _ => None,
+        };
+        check(1, 1_000_000, &[])?;
+        assert_eq!(exceeded(check(1, 1_000_001, &[])), Some(Limit::Single));
+        // 80 a day ago doesn't count against the daily limit anymore, but 80 an hour ago does
+        check(1, 1_000_000, &[(now - SECONDS_PER_DAY, 800_000)])?;
+        assert_eq!(exceeded(check(1, 1_000_000, &[(now - 3600, 800_000)])), Some(Limit::Daily));
+        // the fourth withdrawal within a week
+        let week = [(now - 6 * SECONDS_PER_DAY, 1), (now - 5 * SECONDS_PER_DAY, 1), (now - 2 * SECONDS_PER_DAY, 1)];
+        assert_eq!(exceeded(check(1, 1, &week)), Some(Limit::WeeklyCount));
+        check(1, 1, &week[1..])?;
+        // other tiers have other limits, tiers without limits have none
+        check(7, 5_000_000, &[])?;
+        assert_eq!(exceeded(check(7, 10_000_001, &[])), Some(Limit::Single));
+        check(8, u128::MAX, &week)?;
+
+        assert!("default.monthly = 1".parse::<Limits>().is_err());
+        assert!("default.daily_count = 1.5".parse::<Limits>().is_err());
+        assert!("default.daily = -1".parse::<Limits>().is_err());
+        assert!("client bob = vip".parse::<Limits>().is_err());
+        assert!("client 1 = v i p".parse::<Limits>().is_err());
+        assert!("single = 1".parse::<Limits>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,38 @@
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::Level;
+
+use crate::error::{Error, Result};
+
+// LogOptions configures where diagnostics go and how verbose they are
+// stdout is reserved for the output of the engine, so diagnostics go to stderr unless a log file is given
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogOptions {
+    pub level: Level,
+    pub file: Option<PathBuf>,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        Self {
+            level: Level::INFO,
+            file: None,
+        }
+    }
+}
+
+// init installs the global logger
+pub fn init(opts: &LogOptions) -> Result<()> {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(opts.level)
+        .with_target(false);
+    let res = match opts.file {
+        Some(ref path) => builder
+            .with_ansi(false)
+            .with_writer(Mutex::new(File::create(path)?))
+            .try_init(),
+        None => builder.with_writer(std::io::stderr).try_init(),
+    };
+    res.map_err(|_| Error::InvalidArguments)
+}
@@ -1,34 +1,34 @@
-use crossbeam::channel::bounded;
-use csv_async::{AsyncReaderBuilder, AsyncSerializer, Trim};
+use csv_async::AsyncSerializer;
 use std::env;
-use std::path::Path;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::fs::File;
-use tokio::sync::Mutex;
 use tokio::runtime::Builder as RuntimeBuilder;
-use tokio_stream::StreamExt;
 
-mod types;
-use types::*;
-
-mod storage;
-use storage::*;
-
-mod error;
-use error::{Error, Result};
-
-mod accounts;
-use accounts::Manager as AccountManager;
+use tx_engine::cli::{self, AtOptions, Command, GenerateOptions, Point, ProcessOptions};
+use tx_engine::error::Result;
+use tx_engine::generate::{self, Generator};
+use tx_engine::history;
+use tx_engine::logging;
+use tx_engine::metrics::{self, Metrics};
+use tx_engine::pipeline::{self, Columns};
+use tx_engine::summary::Summary;
 
 // main entry point of the application
 fn main() -> Result<()> {
     // Get the command line arguments
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <transaction-csv-file>", args[0]);
-        return Err(Error::InvalidArguments);
-    }
-    let file_path = Path::new(&args[1]);
+    // stdout is reserved for the output, so usage and diagnostics go to stderr
+    let mut args: Vec<String> = env::args().collect();
+    let command = match cli::parse_log_options(&mut args).and_then(|log| {
+        logging::init(&log)?;
+        cli::parse(&args[1..])
+    }) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}", cli::usage(&args[0]));
+            return Err(e);
+        }
+    };
 
     // Create the tokio runtime
     let worker_threads = match num_cpus::get(){
@@ -42,94 +42,74 @@ fn main() -> Result<()> {
 
     // Run async code
     runtime.block_on(async {
-        // create a transaction store, this is needed to lookup transactions that are on dispute
-        // this should be backed by a file based key value store, for now its in-memory (@TODO)
-        let tx_store = Arc::new(Mutex::new(
-            InMemoryKVStore::<TransactionID, Transaction>::new()?,
-        ));
-
-        // create a account store
-        // this is ok to be backed by a in-memory store, since we can't have more than ~65k accounts
-        // Note that this abstraction will introduce a not insignificant performance cost, but it would enable us to easily upgrade to a persistent store
-        let account_store = Arc::new(Mutex::new(InMemoryKVStore::<ClientID, Account>::new()?));
-
-        // create account manager which will apply transactions to accounts
-        let mut account_manager = AccountManager::new(account_store.clone(), tx_store.clone());
-
-        // create a channel to receive transactions
-        let (tx, rx) = bounded(1 << 10);
-
-        // try to open the file
-        let file = File::open(file_path).await?;
-
-        // kickoff a task that reads the transactions from the csv file and puts them into the channel
-        let reader_task = tokio::spawn(async move {
-            // create a CSV reader
-            let mut reader = AsyncReaderBuilder::new()
-                .trim(Trim::All)
-                .create_deserializer(file);
+        match command {
+            Command::Process(opts) => process(opts).await,
+            Command::At(opts) => at(opts).await,
+            Command::Generate(opts) => generate(opts).await,
+        }
+    })
+}
 
-            // now read the records and feed them to the manager
-            let mut records = reader.deserialize::<TransactionRow>();
-            while let Some(v) = records.next().await {
-                match v {
-                    Ok(v) => {
-                        let v: Transaction = v.into(); // convert from f64 to u64 ro prevent loss of precision
-                        match tx.send(v) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                eprintln!("Error sending transaction to manager: {}", e);
-                            }
-                        };
-                    }
-                    Err(e) => {
-                        println!("Error reading from csv: {}", e);
-                    }
-                }
-            }
-        });
+// process reads all transactions from the input file, applies them and prints the final account states
+async fn process(opts: ProcessOptions) -> Result<()> {
+    // try to open the file
+    let file = File::open(&opts.input).await?;
 
-        // kick off a task that reads the channel and processes the transactions
-        let processing_task = tokio::spawn(async move {
-            // store and process transactions
-            for tx in rx {
-                // store the transaction if its a deposit or withdrawal
-                if tx.type_ == TxType::Deposit || tx.type_ == TxType::Withdrawal {
-                    match tx_store.lock().await.set(tx.tx, tx.clone()) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            eprintln!("Error storing transaction: {}", e);
-                        }
-                    };
-                }
-                // update account balances
-                match account_manager.process_transaction(tx).await {
-                    Ok(()) => {}
-                    Err(err) => {
-                        eprintln!("{}", err)
-                    }
-                };
-            }
-        });
+    let metrics = Arc::new(Metrics::new());
+    if let Some(addr) = opts.metrics_addr {
+        metrics::serve(addr, metrics.clone()).await?;
+    }
+    let started = Instant::now();
+    let outcome = pipeline::run(file, &opts, metrics.clone()).await?;
+    let elapsed = started.elapsed();
+    metrics.log_summary(elapsed);
+    if let Some(ref path) = opts.metrics {
+        tokio::fs::write(path, metrics.render()).await?;
+    }
+    if let Some(ref path) = opts.summary {
+        let accounts = outcome.accounts.clone().into_iter().map(|(_, a)| a);
+        let report = Summary::new(&metrics, &outcome.ledgers, &outcome.assets, accounts, elapsed).to_string();
+        if path.as_os_str() == "-" {
+            eprint!("{}", report);
+        } else {
+            tokio::fs::write(path, report).await?;
+        }
+    }
 
-        // wait for the reader and processing tasks to finish
-        let (r1, r2) = tokio::join!(reader_task, processing_task);
-        r1?;
-        r2?;
+    // output final account state
+    let columns = Columns {
+        pending_credit: opts.pending_credit,
+        deficit: opts.deficit,
+    };
+    pipeline::write_accounts(outcome.accounts, &outcome.assets, columns, tokio::io::stdout()).await
+}
 
-        // output final account state
-        let mut writer = AsyncSerializer::from_writer(tokio::io::stdout());
-        let store = account_store.lock().await.clone();
-        for (_, account) in store.into_iter() {
-            let row: AccountRow = account.into(); // convert from u64 to f64 to present account data in final format
-            match writer.serialize(row).await {
-                Ok(_) => {}
-                Err(e) => eprintln!("Error writing account: {}", e),
-            };
-        }
+// at prints the state of the accounts of a client at a given point in a recorded history
+async fn at(opts: AtOptions) -> Result<()> {
+    let history = history::load(&opts.history).await?;
+    let seq = match opts.point {
+        Point::Seq(seq) => seq,
+        Point::Tx(tx) => history.seq_of(tx)?,
+    };
+    let accounts = history.accounts_at(opts.client, seq).await?;
 
-        Ok::<(), Error>(())
-    })?;
+    let mut writer = AsyncSerializer::from_writer(tokio::io::stdout());
+    for row in history.assets().account_rows(accounts) {
+        writer.serialize(row).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
 
+// generate writes deterministic test data and optionally the final account states the engine is expected to produce
+async fn generate(opts: GenerateOptions) -> Result<()> {
+    let mut generator = Generator::new(opts.config);
+    match opts.output {
+        Some(path) => generate::write_transactions(&mut generator, File::create(path).await?).await?,
+        None => generate::write_transactions(&mut generator, tokio::io::stdout()).await?,
+    }
+    if let Some(path) = opts.expected {
+        generate::write_expected(&generator, File::create(path).await?).await?;
+    }
     Ok(())
 }
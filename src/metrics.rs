@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+use crate::{error::Result, types::TxType};
+
+// the result label of transactions that were applied successfully
+pub const APPLIED: &str = "applied";
+
+// upper bounds of the apply latency histogram buckets in seconds
+const LATENCY_BUCKETS: [f64; 8] = [1e-6, 2.5e-6, 5e-6, 1e-5, 2.5e-5, 1e-4, 1e-3, 1e-2];
+
+// Metrics collects counters and gauges of a pipeline run
+// It is shared between the tasks of the pipeline, so everything is updated through atomics or behind a mutex
+#[derive(Debug, Default)]
+pub struct Metrics {
+    rows_read: AtomicU64,
+    rows_unparsable: AtomicU64,
+    // processed transactions by type and result, the result is either "applied" or the kind of error
+    transactions: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    channel_depth: AtomicU64,
+    channel_depth_max: AtomicU64,
+    // one counter per latency bucket plus the +Inf bucket
+    apply_latency_buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    apply_latency_sum_nanos: AtomicU64,
+    accounts: AtomicU64,
+    transactions_stored: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn row_read(&self) {
+        self.rows_read.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn row_unparsable(&self) {
+        self.rows_unparsable.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // transaction_processed records the outcome and duration of one `process_transaction` call
+    pub fn transaction_processed(&self, type_: &TxType, rejected: Option<&'static str>, duration: Duration) {
+        let result = rejected.unwrap_or(APPLIED);
+        *self
+            .transactions
+            .lock()
+            .unwrap()
+            .entry((type_.as_str(), result))
+            .or_default() += 1;
+        let secs = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|le| secs <= *le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.apply_latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.apply_latency_sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn rows_read(&self) -> u64 {
+        self.rows_read.load(Ordering::Relaxed)
+    }
+
+    pub fn rows_unparsable(&self) -> u64 {
+        self.rows_unparsable.load(Ordering::Relaxed)
+    }
+
+    // transactions returns the number of processed transactions by type and result
+    pub fn transactions(&self) -> BTreeMap<(&'static str, &'static str), u64> {
+        self.transactions.lock().unwrap().clone()
+    }
+
+    pub fn set_channel_depth(&self, depth: usize) {
+        self.channel_depth.store(depth as u64, Ordering::Relaxed);
+        self.channel_depth_max.fetch_max(depth as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_store_sizes(&self, accounts: usize, transactions: usize) {
+        self.accounts.store(accounts as u64, Ordering::Relaxed);
+        self.transactions_stored.store(transactions as u64, Ordering::Relaxed);
+    }
+
+    // render formats all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, type_: &str, help: &str, samples: &[(String, String)]| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, type_);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+        let value = |v: &AtomicU64| v.load(Ordering::Relaxed).to_string();
+
+        metric(
+            "tx_engine_rows_read_total",
+            "counter",
+            "Rows read from the input, including unparsable ones.",
+            &[(String::new(), value(&self.rows_read))],
+        );
+        metric(
+            "tx_engine_rows_unparsable_total",
+            "counter",
+            "Rows that could not be parsed.",
+            &[(String::new(), value(&self.rows_unparsable))],
+        );
+        let transactions: Vec<(String, String)> = self
+            .transactions()
+            .iter()
+            .map(|((type_, result), count)| {
+                (format!("{{type=\"{}\",result=\"{}\"}}", type_, result), count.to_string())
+            })
+            .collect();
+        metric(
+            "tx_engine_transactions_total",
+            "counter",
+            "Transactions processed by the account manager, by type and result (applied or the reason of the rejection).",
+            &transactions,
+        );
+        metric(
+            "tx_engine_channel_depth",
+            "gauge",
+            "Transactions waiting in the channel between reader and processor.",
+            &[(String::new(), value(&self.channel_depth))],
+        );
+        metric(
+            "tx_engine_channel_depth_max",
+            "gauge",
+            "Highest observed channel depth.",
+            &[(String::new(), value(&self.channel_depth_max))],
+        );
+
+        let mut samples = Vec::new();
+        let mut cumulative = 0;
+        for (idx, bucket) in self.apply_latency_buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = LATENCY_BUCKETS
+                .get(idx)
+                .map(|le| le.to_string())
+                .unwrap_or_else(|| "+Inf".to_string());
+            samples.push((format!("_bucket{{le=\"{}\"}}", le), cumulative.to_string()));
+        }
+        let sum = self.apply_latency_sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        samples.push(("_sum".to_string(), sum.to_string()));
+        samples.push(("_count".to_string(), cumulative.to_string()));
+        metric(
+            "tx_engine_apply_duration_seconds",
+            "histogram",
+            "Duration of applying a single transaction.",
+            &samples,
+        );
+
+        metric(
+            "tx_engine_accounts",
+            "gauge",
+            "Accounts in the account store.",
+            &[(String::new(), value(&self.accounts))],
+        );
+        metric(
+            "tx_engine_transactions_stored",
+            "gauge",
+            "Transactions in the transaction store.",
+            &[(String::new(), value(&self.transactions_stored))],
+        );
+        out
+    }
+
+    // log_summary logs the most important metrics, this is used at the end of a batch run
+    pub fn log_summary(&self, elapsed: Duration) {
+        let transactions = self.transactions();
+        let applied: u64 = transactions.iter().filter(|((_, r), _)| *r == APPLIED).map(|(_, c)| c).sum();
+        let rejected: u64 = transactions.values().sum::<u64>() - applied;
+        let apply_secs = self.apply_latency_sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        info!(
+            rows_read = self.rows_read.load(Ordering::Relaxed),
+            rows_unparsable = self.rows_unparsable.load(Ordering::Relaxed),
+            applied,
+            rejected,
+            channel_depth_max = self.channel_depth_max.load(Ordering::Relaxed),
+            apply_secs,
+            elapsed_secs = elapsed.as_secs_f64(),
+            accounts = self.accounts.load(Ordering::Relaxed),
+            transactions_stored = self.transactions_stored.load(Ordering::Relaxed),
+            "run finished"
+        );
+    }
+}
+
+// serve exposes the metrics on `http://<addr>/metrics` (every path returns them) until the process exits
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(%addr, "serving metrics");
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!(error = %e, "error accepting metrics connection");
+                    continue;
+                }
+            };
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                // the request itself is not interesting, every request gets the metrics
+                let mut buf = [0u8; 1024];
+                let _ = socket.read(&mut buf).await;
+                let body = metrics.render();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.row_read();
+        metrics.row_read();
+        metrics.row_unparsable();
+        metrics.transaction_processed(&TxType::Deposit, None, Duration::from_nanos(500));
+        metrics.transaction_processed(&TxType::Withdrawal, Some("insufficient_funds"), Duration::from_millis(5));
+        metrics.set_channel_depth(10);
+        metrics.set_channel_depth(3);
+        metrics.set_store_sizes(2, 1);
+
+        let out = metrics.render();
+        assert!(out.contains("# TYPE tx_engine_rows_read_total counter\ntx_engine_rows_read_total 2\n"));
+        assert!(out.contains("tx_engine_rows_unparsable_total 1\n"));
+        assert!(out.contains("tx_engine_transactions_total{type=\"deposit\",result=\"applied\"} 1\n"));
+        assert!(out.contains("tx_engine_transactions_total{type=\"withdrawal\",result=\"insufficient_funds\"} 1\n"));
+        assert!(out.contains("tx_engine_channel_depth 3\n"));
+        assert!(out.contains("tx_engine_channel_depth_max 10\n"));
+        assert!(out.contains("tx_engine_apply_duration_seconds_bucket{le=\"0.000001\"} 1\n"));
+        assert!(out.contains("tx_engine_apply_duration_seconds_bucket{le=\"0.001\"} 1\n"));
+        assert!(out.contains("tx_engine_apply_duration_seconds_bucket{le=\"0.01\"} 2\n"));
+        assert!(out.contains("tx_engine_apply_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(out.contains("tx_engine_apply_duration_seconds_count 2\n"));
+        assert!(out.contains("tx_engine_accounts 2\n"));
+        assert!(out.contains("tx_engine_transactions_stored 1\n"));
+    }
+}
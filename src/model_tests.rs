@@ -0,0 +1,206 @@
+// Model based tests for the account manager
+// Arbitrary sequences of transactions are applied to the manager and to a simple reference model,
+// after every transaction both have to agree on the result and on the balances of the account.
+use proptest::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::{
+    accounts::Manager,
+    policy::{DisputeTracking, Policy},
+    storage::{InMemoryKVStore, KVStore},
+    types::{Account, AccountKey, ClientID, Currency, Transaction, TransactionID, TxType},
+    verify::VerifyMode,
+};
+
+const CLIENTS: ClientID = 4;
+const MAX_AMOUNT: u64 = 1_000_000;
+
+// Model is a straightforward reference implementation of the business rules
+#[derive(Debug, Default)]
+struct Model {
+    accounts: HashMap<ClientID, (i128, i128, bool)>, // available, held, locked
+    txs: HashMap<TransactionID, (TxType, Option<u128>)>,
+    // whether the disputes are tracked per transaction, otherwise they act on the held balance of the account
+    tracked: bool,
+    // the open disputes with the client and the amount they hold back
+    disputes: HashMap<TransactionID, (ClientID, i128)>,
+    // the transactions that were charged back, they can't be disputed again
+    charged_back: HashSet<TransactionID>,
+}
+
+impl Model {
+    // apply returns false if the transaction is rejected
+    fn apply(&mut self, tx: &Transaction) -> bool {
+        if tx.type_ == TxType::Deposit || tx.type_ == TxType::Withdrawal {
+            self.txs.insert(tx.tx, (tx.type_.clone(), tx.amount));
+        }
+        let (available, held, locked) = self.accounts.entry(tx.client).or_default();
+        if *locked {
+            return false;
+        }
+        let source = match tx.type_ {
+            TxType::Deposit | TxType::Withdrawal => None,
+            _ => match self.txs.get(&tx.tx) {
+                Some(source) => Some(source.clone()),
+                None => return false,
+            },
+        };
+        // transactions without an amount don't change anything
+        let amount = match source {
+            Some((_, amount)) => amount,
+            None => tx.amount,
+        };
+        let amount = match amount {
+            Some(amount) => amount as i128,
+            None => return true,
+        };
+        let from_deposit = matches!(source, Some((TxType::Deposit, _)));
+        let from_withdrawal = matches!(source, Some((TxType::Withdrawal, _)));
+        match tx.type_ {
+            TxType::Deposit => *available += amount,
+            TxType::Withdrawal => {
+                if *available < amount {
+                    return false;
+                }
+                *available -= amount;
+            }
+            TxType::Dispute if from_deposit && !self.tracked => {
+                let amount = amount.min(*available);
+                *available -= amount;
+                *held += amount;
+            }
+            TxType::Resolve if from_deposit && !self.tracked => {
+                let amount = amount.min(*held);
+                *held -= amount;
+                *available += amount;
+            }
+            TxType::Chargeback if from_deposit && !self.tracked => {
+                *held -= amount.min(*held);
+                *locked = true;
+            }
+            TxType::Dispute if from_deposit => {
+                if self.disputes.contains_key(&tx.tx) || self.charged_back.contains(&tx.tx) {
+                    return false;
+                }
+                let amount = amount.min(*available);
+                *available -= amount;
+                *held += amount;
+                self.disputes.insert(tx.tx, (tx.client, amount));
+            }
+            TxType::Resolve | TxType::Chargeback if from_deposit => {
+                let held_back = match self.disputes.get(&tx.tx) {
+                    Some((client, amount)) if *client == tx.client => *amount,
+                    _ => return false,
+                };
+                self.disputes.remove(&tx.tx);
+                *held -= held_back;
+                if tx.type_ == TxType::Resolve {
+                    *available += held_back;
+                } else {
+                    *locked = true;
+                    self.charged_back.insert(tx.tx);
+                }
+            }
+            // a withdrawal is charged back whether it's disputed or not
+            TxType::Chargeback if from_withdrawal => *available += amount,
+            _ => {}
+        }
+        true
+    }
+}
+
+// Op is a generated transaction, deposits and withdrawals get their transaction id from their position
+#[derive(Debug, Clone)]
+enum Op {
+    Deposit(ClientID, Option<u64>),
+    Withdrawal(ClientID, Option<u64>),
+    Dispute(ClientID, TransactionID),
+    Resolve(ClientID, TransactionID),
+    Chargeback(ClientID, TransactionID),
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let client = 1..=CLIENTS;
+    let amount = prop_oneof![9 => (1..MAX_AMOUNT).prop_map(Some), 1 => Just(None)];
+    let tx = 1..64 as TransactionID;
+    prop_oneof![
+        4 => (client.clone(), amount.clone()).prop_map(|(c, a)| Op::Deposit(c, a)),
+        3 => (client.clone(), amount).prop_map(|(c, a)| Op::Withdrawal(c, a)),
+        2 => (client.clone(), tx.clone()).prop_map(|(c, t)| Op::Dispute(c, t)),
+        1 => (client.clone(), tx.clone()).prop_map(|(c, t)| Op::Resolve(c, t)),
+        1 => (client, tx).prop_map(|(c, t)| Op::Chargeback(c, t)),
+    ]
+}
+
+fn to_transaction(idx: usize, op: &Op) -> Transaction {
+    let (type_, client, tx, amount) = match *op {
+        Op::Deposit(c, a) => (TxType::Deposit, c, idx as TransactionID + 1, a),
+        Op::Withdrawal(c, a) => (TxType::Withdrawal, c, idx as TransactionID + 1, a),
+        Op::Dispute(c, t) => (TxType::Dispute, c, t, None),
+        Op::Resolve(c, t) => (TxType::Resolve, c, t, None),
+        Op::Chargeback(c, t) => (TxType::Chargeback, c, t, None),
+    };
+    Transaction {
+        type_,
+        client,
+        tx,
+        amount: amount.map(u128::from),
+        currency: Currency::DEFAULT,
+        destination: None,
+        fee: 0,
+        charged_back: 0,
+        timestamp: None,
+    }
+}
+
+async fn check(ops: Vec<Op>, dispute_tracking: DisputeTracking) -> std::result::Result<(), TestCaseError> {
+    let account_store = Arc::new(Mutex::new(InMemoryKVStore::<AccountKey, Account>::new().unwrap()));
+    let tx_store = Arc::new(Mutex::new(
+        InMemoryKVStore::<TransactionID, Transaction>::new().unwrap(),
+    ));
+    let policy = Policy {
+        dispute_tracking,
+        ..Default::default()
+    };
+    let mut mgr = Manager::new(account_store.clone(), tx_store)
+        .with_verify_mode(VerifyMode::EachTransaction)
+        .with_policy(policy);
+    let mut model = Model {
+        tracked: dispute_tracking == DisputeTracking::Transaction,
+        ..Default::default()
+    };
+
+    for (idx, op) in ops.iter().enumerate() {
+        let tx = to_transaction(idx, op);
+        let accepted = model.apply(&tx);
+        let res = mgr.process_transaction(tx.clone()).await;
+        prop_assert_eq!(accepted, res.is_ok(), "tx {:?} returned {:?}", tx, res);
+
+        let store = account_store.lock().await;
+        let account = store.get((tx.client, Currency::DEFAULT)).unwrap();
+        let (available, held, locked) = model.accounts[&tx.client];
+        prop_assert_eq!(account.available as i128, available, "available of {:?}", account);
+        prop_assert_eq!(account.held as i128, held, "held of {:?}", account);
+        prop_assert_eq!(account.total as i128, available + held, "total of {:?}", account);
+        prop_assert_eq!(account.locked, locked, "locked of {:?}", account);
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(512))]
+
+    #[test]
+    fn test_manager_matches_model(ops in prop::collection::vec(op(), 1..200)) {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(check(ops, DisputeTracking::Account))?;
+    }
+
+    #[test]
+    fn test_manager_matches_model_with_tracked_disputes(ops in prop::collection::vec(op(), 1..200)) {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(check(ops, DisputeTracking::Transaction))?;
+    }
+}
@@ -0,0 +1,342 @@
+use crossbeam::channel::bounded;
+use csv_async::{AsyncDeserializer, AsyncReaderBuilder, AsyncSerializer, Trim};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use crate::{
+    accounts::{Applied, Manager as AccountManager},
+    assets::Assets,
+    cli::ProcessOptions,
+    error::{Error, Result},
+    fees::FeeSchedule,
+    history::Journal,
+    hooks::Hooks,
+    limits::Limits,
+    metrics::{Metrics, APPLIED},
+    policy::Policy,
+    storage::{InMemoryKVStore, KVStore},
+    types::{Account, AccountKey, AccountRow, ResultRow, Transaction, TransactionID, TransactionRow},
+    verify::{self, Ledgers, VerifyMode},
+};
+
+// reader creates the csv reader for transaction files
+pub fn reader<R: AsyncRead + Unpin + Send + Sync>(input: R) -> AsyncDeserializer<R> {
+    AsyncReaderBuilder::new().trim(Trim::All).create_deserializer(input)
+}
+
+// the store sizes are published to the metrics every this many transactions
+const STORE_SIZE_INTERVAL: u64 = 4096;
+
+// Outcome is the result of a pipeline run
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    // the final state of all accounts
+    pub accounts: InMemoryKVStore<AccountKey, Account>,
+    // the volumes moved by all applied transactions per currency
+    pub ledgers: Ledgers,
+    // the precision of the currencies, it's needed to present the amounts
+    pub assets: Assets,
+}
+
+// run reads all transactions from the input, applies them and returns the final account states
+// The progress of the run is published to the given metrics
+pub async fn run<R>(input: R, opts: &ProcessOptions, metrics: Arc<Metrics>) -> Result<Outcome>
+where
+    R: AsyncRead + Unpin + Send + Sync + 'static,
+{
+    run_with_hooks(input, opts, metrics, Hooks::new()).await
+}
+
+// run_with_hooks is `run` with validators and observers on the account manager, see `hooks`
+pub async fn run_with_hooks<R>(input: R, opts: &ProcessOptions, metrics: Arc<Metrics>, hooks: Hooks) -> Result<Outcome>
+where
+    R: AsyncRead + Unpin + Send + Sync + 'static,
+{
+    // create a transaction store, this is needed to lookup transactions that are on dispute
+    // this should be backed by a file based key value store, for now its in-memory (@TODO)
+    let tx_store = Arc::new(Mutex::new(
+        InMemoryKVStore::<TransactionID, Transaction>::new()?,
+    ));
+
+    // create a account store
+    // this is ok to be backed by a in-memory store, since we can't have more than ~65k accounts
+    // Note that this abstraction will introduce a not insignificant performance cost, but it would enable us to easily upgrade to a persistent store
+    let account_store = Arc::new(Mutex::new(InMemoryKVStore::<AccountKey, Account>::new()?));
+
+    // load the business rules, without a policy file the default rules apply
+    let policy = match opts.policy {
+        Some(ref path) => Policy::load(path).await?,
+        None => Policy::default(),
+    };
+
+    // load the precision of the currencies, without an assets file all of them have the default precision
+    let assets = match opts.assets {
+        Some(ref path) => Assets::load(path).await?,
+        None => Assets::default(),
+    };
+
+    // load the fees, without a fee schedule no fees are charged
+    let fees = match opts.fees {
+        Some(ref path) => FeeSchedule::load(path).await?,
+        None => FeeSchedule::default(),
+    };
+
+    // load the limits on withdrawals, without a limits file there are none
+    let limits = match opts.limits {
+        Some(ref path) => Limits::load(path).await?,
+        None => Limits::default(),
+    };
+
+    // create account manager which will apply transactions to accounts
+    let mut account_manager = AccountManager::new(account_store.clone(), tx_store.clone())
+        .with_verify_mode(opts.verify)
+        .with_policy(policy)
+        .with_fees(fees.clone())
+        .with_limits(limits.clone())
+        .with_hooks(hooks)
+        .with_assets(assets.clone());
+
+    // create a channel to receive transactions
+    let (tx, rx) = bounded(1 << 10);
+
+    // create the journal if the history of this run should be persisted
+    let mut journal = match opts.history {
+        Some(ref dir) => Some(Journal::create(dir, opts.snapshot_interval, &policy, &assets, &fees, &limits).await?),
+        None => None,
+    };
+
+    // create the results file if the result of every transaction should be written
+    let mut results = match opts.results {
+        Some(ref path) => Some(AsyncSerializer::from_writer(File::create(path).await?)),
+        None => None,
+    };
+
+    // kickoff a task that reads the transactions from the csv file and puts them into the channel
+    let reader_metrics = metrics.clone();
+    let reader_assets = assets.clone();
+    let reader_task = tokio::spawn(async move {
+        // create a CSV reader
+        let mut reader = reader(input);
+
+        // now read the records and feed them to the manager
+        let mut records = reader.deserialize::<TransactionRow>();
+        while let Some(v) = records.next().await {
+            reader_metrics.row_read();
+            match v {
+                Ok(v) => {
+                    // admin operations are only accepted if they name the operator that performed them
+                    let admin = v.admin().filter(|_| v.type_.is_admin());
+                    if v.type_.is_admin() && admin.is_none() {
+                        reader_metrics.row_unparsable();
+                        warn!(tx = v.tx, client = v.client, tx_type = ?v.type_, "admin operation without operator");
+                        continue;
+                    }
+                    // convert the amount into an integer of the smallest unit of the currency to prevent loss of precision
+                    let (tx_id, client) = (v.tx, v.client);
+                    let v = match reader_assets.transaction(v) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            reader_metrics.row_unparsable();
+                            warn!(tx = tx_id, client, error = %e, "invalid amount");
+                            continue;
+                        }
+                    };
+                    match tx.send((v, admin)) {
+                        Ok(_) => {}
+                        Err(e) => {
+                            error!(error = %e, "error sending transaction to manager");
+                        }
+                    };
+                }
+                Err(e) => {
+                    reader_metrics.row_unparsable();
+                    let line = e.position().map(|p| p.line());
+                    warn!(line, error = %e, "error reading from csv");
+                }
+            }
+        }
+    }.instrument(info_span!("reader")));
+
+    // kick off a task that reads the channel and processes the transactions
+    let snapshot_store = account_store.clone();
+    let processing_tx_store = tx_store.clone();
+    let processing_assets = assets.clone();
+    let processing_task = tokio::spawn(async move {
+        // record and process transactions
+        let mut processed = 0u64;
+        while let Ok((tx, admin)) = rx.recv() {
+            metrics.set_channel_depth(rx.len());
+            // update account balances
+            let (tx_id, client, type_) = (tx.tx, tx.client, tx.type_.clone());
+            let started = Instant::now();
+            let res = account_manager.process_transaction(tx.clone()).await;
+            metrics.transaction_processed(&type_, res.as_ref().err().map(Error::kind), started.elapsed());
+            // the event is recorded with the fee that was charged, so a replayed chargeback can refund it, and with
+            // its result, so a replay without the validators of this run skips the ones they rejected
+            if let Some(journal) = journal.as_mut() {
+                let fee = res.as_ref().map_or(0, |applied| applied.fee);
+                let result = res.as_ref().err().map_or(APPLIED, Error::kind);
+                journal.record(&Transaction { fee, ..tx.clone() }, admin.as_ref(), result).await?;
+            }
+            if let Some(results) = results.as_mut() {
+                results.serialize(result_row(&processing_assets, &tx, &res)).await?;
+            }
+            match res {
+                Ok(applied) => {
+                    debug!(
+                        tx = tx_id,
+                        client,
+                        tx_type = ?type_,
+                        currency = %applied.currency,
+                        fee = %format_fee(&processing_assets, &applied, applied.fee),
+                        refunded_fee = %format_fee(&processing_assets, &applied, applied.refunded_fee),
+                        "transaction applied"
+                    );
+                    for flag in &applied.flags {
+                        warn!(tx = tx_id, client, tx_type = ?type_, flag, "transaction flagged");
+                    }
+                    if let Some(admin) = admin {
+                        info!(
+                            tx = tx_id,
+                            client,
+                            tx_type = ?type_,
+                            operator = admin.operator,
+                            reason = admin.reason,
+                            "admin operation applied"
+                        );
+                    }
+                }
+                // a broken invariant means the engine itself is broken, so we stop right here
+                Err(err @ Error::InvariantViolation(_)) => {
+                    error!(tx = tx_id, client, tx_type = ?type_, error = %err, "invariant violated");
+                    return Err(err);
+                }
+                Err(err) => {
+                    info!(tx = tx_id, client, tx_type = ?type_, error = %err, "transaction rejected");
+                }
+            };
+            if let Some(journal) = journal.as_mut() {
+                if journal.snapshot_due() {
+                    let accounts = snapshot_store.lock().await.clone();
+                    let holds = account_manager.holds().cloned();
+                    let withdrawals = account_manager.withdrawals();
+                    let chargebacks = account_manager.chargebacks();
+                    let clock = account_manager.clock();
+                    journal
+                        .write_snapshot(accounts.into_iter().map(|(_, a)| a), holds, withdrawals, chargebacks, clock)
+                        .await?;
+                }
+            }
+            processed += 1;
+            if processed.is_multiple_of(STORE_SIZE_INTERVAL) {
+                publish_store_sizes(&metrics, &snapshot_store, &processing_tx_store).await;
+            }
+        }
+        publish_store_sizes(&metrics, &snapshot_store, &processing_tx_store).await;
+        if let Some(journal) = journal {
+            journal.finish().await?;
+        }
+        if let Some(mut results) = results {
+            results.flush().await?;
+        }
+        Ok::<_, Error>(account_manager)
+    }.instrument(info_span!("processing")));
+
+    // wait for the reader and processing tasks to finish
+    let (r1, r2) = tokio::join!(reader_task, processing_task);
+    r1?;
+    let account_manager = r2??;
+
+    if account_manager.verify_mode() == VerifyMode::End {
+        let accounts = account_store.lock().await.clone();
+        verify::check_accounts(accounts.into_iter().map(|(_, a)| a), account_manager.ledgers())?;
+    }
+
+    let accounts = account_store.lock().await.clone();
+    Ok(Outcome {
+        accounts,
+        ledgers: account_manager.ledgers().clone(),
+        assets,
+    })
+}
+
+// format_fee formats a fee of the applied transaction with the precision of its currency
+fn format_fee(assets: &Assets, applied: &Applied, fee: u128) -> String {
+    assets.format_amount(applied.currency, i128::try_from(fee).unwrap_or(i128::MAX))
+}
+
+// result_row describes the result of the transaction for the results file
+fn result_row(assets: &Assets, tx: &Transaction, res: &Result<Applied>) -> ResultRow {
+    let row = ResultRow {
+        type_: tx.type_.clone(),
+        client: tx.client,
+        tx: tx.tx,
+        result: APPLIED.to_string(),
+        currency: tx.currency,
+        fee: None,
+        refunded_fee: None,
+    };
+    match res {
+        Ok(applied) => ResultRow {
+            currency: applied.currency,
+            fee: Some(format_fee(assets, applied, applied.fee)),
+            refunded_fee: Some(format_fee(assets, applied, applied.refunded_fee)),
+            ..row
+        },
+        Err(err) => ResultRow {
+            result: err.kind().to_string(),
+            ..row
+        },
+    }
+}
+
+async fn publish_store_sizes<A: KVStore, T: KVStore>(metrics: &Metrics, accounts: &Mutex<A>, transactions: &Mutex<T>) {
+    let accounts = accounts.lock().await.len();
+    let transactions = transactions.lock().await.len();
+    metrics.set_store_sizes(accounts, transactions);
+}
+
+// Columns are the optional columns of the account states, they are only written if they are asked for
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Columns {
+    pub pending_credit: bool,
+    pub deficit: bool,
+}
+
+// write_accounts writes the given account states as csv, one row per client and currency
+// The balances are formatted with the precision of their currency
+pub async fn write_accounts<W: AsyncWrite + Unpin>(
+    accounts: InMemoryKVStore<AccountKey, Account>,
+    assets: &Assets,
+    columns: Columns,
+    writer: W,
+) -> Result<()> {
+    let mut writer = AsyncSerializer::from_writer(writer);
+    let accounts: Vec<Account> = accounts.into_iter().map(|(_, account)| account).collect();
+    let optional: Vec<_> = accounts
+        .iter()
+        .map(|account| {
+            let credit = columns.pending_credit.then(|| assets.format_amount(account.currency, account.pending_credit));
+            (credit, columns.deficit.then(|| account.in_deficit()))
+        })
+        .collect();
+    for (row, (credit, deficit)) in assets.account_rows(accounts).into_iter().zip(optional) {
+        let row_id = row.id;
+        let row = AccountRow {
+            pending_credit: credit,
+            deficit,
+            ..row
+        };
+        match writer.serialize(row).await {
+            Ok(_) => {}
+            Err(e) => error!(client = row_id, error = %e, "error writing account"),
+        };
+    }
+    writer.flush().await?;
+    Ok(())
+}
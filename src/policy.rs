@@ -0,0 +1,359 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{
+    error::{Error, Result},
+    types::{TxType, SECONDS_PER_DAY},
+};
+
+// DisputeHold controls what happens when a disputed deposit is larger than the available balance,
+// which happens when the deposited money was already (partly) withdrawn
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DisputeHold {
+    // hold back only what is still available
+    #[default]
+    Clamp,
+    // reject the dispute with `InsufficientFunds`
+    Reject,
+    // hold back the full amount, the available balance goes negative and the account is in deficit
+    Full,
+}
+
+// DisputeAmounts controls what the amount of a dispute, resolve or chargeback row means
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DisputeAmounts {
+    // the amount is ignored and the whole transaction is disputed, inputs often carry arbitrary amounts in these rows
+    #[default]
+    Ignore,
+    // the amount is the part of the transaction that is disputed, resolved or charged back, rows without one
+    // dispute all of it that isn't disputed yet, or resolve or charge back all of it that is
+    Partial,
+}
+
+// DisputeTracking controls what the disputes on deposits and transfers act on
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DisputeTracking {
+    // the money the account holds back for disputes: every dispute holds back the amount of the transaction, a
+    // resolve or chargeback frees as much of it as the account holds back, whether the transaction is disputed or not
+    // No hold is recorded per transaction, so a resolve or chargeback of a transaction that isn't disputed frees the
+    // money held back for the disputes of other transactions of the account, `Transaction` doesn't
+    #[default]
+    Account,
+    // the dispute of the transaction: it can only be disputed once at a time, a resolve or chargeback frees exactly
+    // what its dispute held back and is rejected with `NotDisputed` without an open dispute
+    Transaction,
+}
+
+// WithdrawalDisputes controls how disputes and resolves on withdrawals are handled
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WithdrawalDisputes {
+    // accept them without touching the balances, chargebacks don't need a dispute
+    #[default]
+    Ignore,
+    // a dispute records the claim of the client to get the withdrawn amount back as pending credit, a resolve
+    // discards it and only a claimed amount can be charged back
+    Claim,
+    // reject them with `NotPermitted`
+    Reject,
+}
+
+// WithdrawalChargebacks controls how chargebacks on withdrawals are handled
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WithdrawalChargebacks {
+    // credit the withdrawn amount back to the available balance
+    #[default]
+    Credit,
+    // accept them without touching the balances
+    Ignore,
+    // reject them with `NotPermitted`
+    Reject,
+}
+
+// LockOn controls which chargebacks lock the account
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LockOn {
+    // only chargebacks on deposits, the account holder is the disadvantaged party of a withdrawal chargeback
+    #[default]
+    DepositChargeback,
+    AnyChargeback,
+    Never,
+}
+
+// LockedAccepts lists the client transactions that are still accepted on locked accounts,
+// by default a locked account rejects all of them
+// Admin operations are always accepted, otherwise a locked account could never be unlocked
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LockedAccepts {
+    pub deposit: bool,
+    pub withdrawal: bool,
+    pub dispute: bool,
+    pub resolve: bool,
+    pub chargeback: bool,
+    pub authorize: bool,
+    pub capture: bool,
+    pub void: bool,
+}
+
+impl LockedAccepts {
+    pub fn accepts(&self, type_: &TxType) -> bool {
+        match type_ {
+            TxType::Deposit => self.deposit,
+            TxType::Withdrawal => self.withdrawal,
+            TxType::Dispute => self.dispute,
+            TxType::Resolve => self.resolve,
+            TxType::Chargeback => self.chargeback,
+            TxType::Authorize => self.authorize,
+            TxType::Capture => self.capture,
+            TxType::Void => self.void,
+            _ => type_.is_admin(),
+        }
+    }
+}
+
+// Policy holds the business rules for the edge cases of disputes and chargebacks
+// The default is the behavior the engine always had
+//
+// A policy file has one `key = value` pair per line, empty lines and lines starting with `#` are ignored,
+// keys that are missing keep their default:
+//
+//   dispute_hold = clamp | reject | full
+//   dispute_tracking = account | transaction
+//   dispute_amounts = ignore | partial
+//   withdrawal_disputes = ignore | claim | reject
+//   withdrawal_chargebacks = credit | ignore | reject
+//   lock_on = deposit_chargeback | any_chargeback | never
+//   locked_accepts = <comma separated list of deposit, withdrawal, dispute, resolve, chargeback, authorize, capture, void>
+//   authorization_expiry = <number of transactions, 0 never expires>
+//   dispute_window = <number of days, 0 is unlimited>
+//   dispute_deadline = <number of days, 0 is unlimited>
+//
+// Partial disputes and dispute deadlines need `dispute_tracking = transaction`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Policy {
+    pub dispute_hold: DisputeHold,
+    pub dispute_tracking: DisputeTracking,
+    pub dispute_amounts: DisputeAmounts,
+    pub withdrawal_disputes: WithdrawalDisputes,
+    pub withdrawal_chargebacks: WithdrawalChargebacks,
+    pub lock_on: LockOn,
+    pub locked_accepts: LockedAccepts,
+    // an authorization that is neither captured nor voided is released after this many transactions, 0 keeps it forever
+    pub authorization_expiry: u64,
+    // a transaction can only be disputed within this many days after it happened, 0 allows disputes at any time
+    pub dispute_window: u64,
+    // a dispute that is neither resolved nor charged back is resolved this many days after it was opened, 0 keeps it open
+    pub dispute_deadline: u64,
+}
+
+impl Policy {
+    // load reads the policy from the given file
+    pub async fn load(path: &Path) -> Result<Self> {
+        tokio::fs::read_to_string(path).await?.parse()
+    }
+}
+
+// options maps the names used in policy files to the variants of a setting
+macro_rules! options {
+    ($type:ty { $($name:literal => $variant:ident),+ $(,)? }) => {
+        impl FromStr for $type {
+            type Err = Error;
+
+            fn from_str(s: &str) -> Result<Self> {
+                match s {
+                    $($name => Ok(Self::$variant),)+
+                    _ => Err(Error::InvalidPolicy(format!("unknown value `{}`", s))),
+                }
+            }
+        }
+
+        impl fmt::Display for $type {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match self {
+                    $(Self::$variant => write!(f, $name),)+
+                }
+            }
+        }
+    };
+}
+
+options!(DisputeHold { "clamp" => Clamp, "reject" => Reject, "full" => Full });
+options!(DisputeTracking { "account" => Account, "transaction" => Transaction });
+options!(DisputeAmounts { "ignore" => Ignore, "partial" => Partial });
+options!(WithdrawalDisputes { "ignore" => Ignore, "claim" => Claim, "reject" => Reject });
+options!(WithdrawalChargebacks { "credit" => Credit, "ignore" => Ignore, "reject" => Reject });
+options!(LockOn { "deposit_chargeback" => DepositChargeback, "any_chargeback" => AnyChargeback, "never" => Never });
+
+impl FromStr for LockedAccepts {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut accepts = LockedAccepts::default();
+        for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match TxType::from_name(name) {
+                Some(TxType::Deposit) => accepts.deposit = true,
+                Some(TxType::Withdrawal) => accepts.withdrawal = true,
+                Some(TxType::Dispute) => accepts.dispute = true,
+                Some(TxType::Resolve) => accepts.resolve = true,
+                Some(TxType::Chargeback) => accepts.chargeback = true,
+                Some(TxType::Authorize) => accepts.authorize = true,
+                Some(TxType::Capture) => accepts.capture = true,
+                Some(TxType::Void) => accepts.void = true,
+                _ => return Err(Error::InvalidPolicy(format!("unknown transaction type `{}`", name))),
+            }
+        }
+        Ok(accepts)
+    }
+}
+
+impl fmt::Display for LockedAccepts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let types = [
+            TxType::Deposit,
+            TxType::Withdrawal,
+            TxType::Dispute,
+            TxType::Resolve,
+            TxType::Chargeback,
+            TxType::Authorize,
+            TxType::Capture,
+            TxType::Void,
+        ];
+        let names: Vec<&str> = types.iter().filter(|t| self.accepts(t)).map(TxType::as_str).collect();
+        write!(f, "{}", names.join(", "))
+    }
+}
+
+impl FromStr for Policy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut policy = Policy::default();
+        for (idx, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: String| Error::InvalidPolicy(format!("line {}: {}", idx + 1, reason));
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid("expected `key = value`".to_string()))?;
+            let value = value.trim();
+            let res = match key.trim() {
+                "dispute_hold" => value.parse().map(|v| policy.dispute_hold = v),
+                "dispute_tracking" => value.parse().map(|v| policy.dispute_tracking = v),
+                "dispute_amounts" => value.parse().map(|v| policy.dispute_amounts = v),
+                "withdrawal_disputes" => value.parse().map(|v| policy.withdrawal_disputes = v),
+                "withdrawal_chargebacks" => value.parse().map(|v| policy.withdrawal_chargebacks = v),
+                "lock_on" => value.parse().map(|v| policy.lock_on = v),
+                "locked_accepts" => value.parse().map(|v| policy.locked_accepts = v),
+                "authorization_expiry" => value
+                    .parse()
+                    .map(|v| policy.authorization_expiry = v)
+                    .map_err(|_| Error::InvalidPolicy(format!("expected a number of transactions, got `{}`", value))),
+                "dispute_window" => days(value).map(|v| policy.dispute_window = v),
+                "dispute_deadline" => days(value).map(|v| policy.dispute_deadline = v),
+                key => Err(Error::InvalidPolicy(format!("unknown key `{}`", key))),
+            };
+            res.map_err(|err| match err {
+                Error::InvalidPolicy(reason) => invalid(reason),
+                err => err,
+            })?;
+        }
+        // the parts and the deadline of a dispute are kept with the dispute of the transaction
+        if policy.dispute_tracking == DisputeTracking::Account {
+            if policy.dispute_amounts == DisputeAmounts::Partial {
+                return Err(Error::InvalidPolicy("dispute_amounts = partial needs dispute_tracking = transaction".to_string()));
+            }
+            if policy.dispute_deadline > 0 {
+                return Err(Error::InvalidPolicy("dispute_deadline needs dispute_tracking = transaction".to_string()));
+            }
+        }
+        Ok(policy)
+    }
+}
+
+// the policy is written in the file format, so it can be persisted and loaded again
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "dispute_hold = {}", self.dispute_hold)?;
+        writeln!(f, "dispute_tracking = {}", self.dispute_tracking)?;
+        writeln!(f, "dispute_amounts = {}", self.dispute_amounts)?;
+        writeln!(f, "withdrawal_disputes = {}", self.withdrawal_disputes)?;
+        writeln!(f, "withdrawal_chargebacks = {}", self.withdrawal_chargebacks)?;
+        writeln!(f, "lock_on = {}", self.lock_on)?;
+        writeln!(f, "locked_accepts = {}", self.locked_accepts)?;
+        writeln!(f, "authorization_expiry = {}", self.authorization_expiry)?;
+        writeln!(f, "dispute_window = {}", self.dispute_window)?;
+        writeln!(f, "dispute_deadline = {}", self.dispute_deadline)
+    }
+}
+
+// days parses a number of days, the number of seconds they have must fit into a timestamp
+fn days(value: &str) -> Result<u64> {
+    value
+        .parse::<u64>()
+        .ok()
+        .filter(|days| days.checked_mul(SECONDS_PER_DAY).is_some())
+        .ok_or_else(|| Error::InvalidPolicy(format!("expected a number of days, got `{}`", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        assert_eq!("".parse::<Policy>()?, Policy::default());
+
+        let policy: Policy =
+            "# strict partner\n\ndispute_hold = reject\n  lock_on=never\ndispute_tracking = transaction\ndispute_amounts = partial\n"
+                .parse()?;
+        assert_eq!(
+            policy,
+            Policy {
+                dispute_hold: DisputeHold::Reject,
+                dispute_tracking: DisputeTracking::Transaction,
+                dispute_amounts: DisputeAmounts::Partial,
+                lock_on: LockOn::Never,
+                ..Default::default()
+            }
+        );
+        assert_eq!(policy.to_string().parse::<Policy>()?, policy);
+
+        let policy: Policy = "locked_accepts = resolve,chargeback, deposit".parse()?;
+        assert_eq!(
+            policy.locked_accepts,
+            LockedAccepts {
+                deposit: true,
+                resolve: true,
+                chargeback: true,
+                ..Default::default()
+            }
+        );
+        assert!(policy.to_string().contains("locked_accepts = deposit, resolve, chargeback\n"));
+        assert_eq!(policy.to_string().parse::<Policy>()?, policy);
+        assert!(!policy.locked_accepts.accepts(&TxType::Withdrawal));
+        assert!(policy.locked_accepts.accepts(&TxType::Unlock));
+        assert!("locked_accepts = deposit, unlock".parse::<Policy>().is_err());
+
+        let policy: Policy = "authorization_expiry = 1000\nlocked_accepts = void".parse()?;
+        assert_eq!(policy.authorization_expiry, 1000);
+        assert!(policy.locked_accepts.accepts(&TxType::Void));
+        assert_eq!(policy.to_string().parse::<Policy>()?, policy);
+        assert!("authorization_expiry = soon".parse::<Policy>().is_err());
+
+        let policy: Policy = "dispute_window = 120\ndispute_tracking = transaction\ndispute_deadline = 45".parse()?;
+        assert_eq!((policy.dispute_window, policy.dispute_deadline), (120, 45));
+        assert_eq!(policy.to_string().parse::<Policy>()?, policy);
+        assert!("dispute_window = -1".parse::<Policy>().is_err());
+        assert!("dispute_tracking = transaction\ndispute_deadline = 1e9".parse::<Policy>().is_err());
+        // without the dispute of the transaction there are no parts or deadlines of it
+        assert!("dispute_amounts = partial".parse::<Policy>().is_err());
+        assert!("dispute_deadline = 45".parse::<Policy>().is_err());
+
+        assert!("dispute_hold = maybe".parse::<Policy>().is_err());
+        assert!("colour = blue".parse::<Policy>().is_err());
+        assert!("lock_on".parse::<Policy>().is_err());
+        Ok(())
+    }
+}
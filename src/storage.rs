@@ -9,6 +9,11 @@ pub trait KVStore {
 
     fn get(&self, key: Self::Key) -> Result<&Self::Value>;
     fn set(&mut self, key: Self::Key, value: Self::Value) -> Result<()>;
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -36,6 +41,10 @@ impl<K: Eq + Hash, T: Serialize> KVStore for InMemoryKVStore<K, T> {
         self.store.insert(key, value);
         Ok(())
     }
+
+    fn len(&self) -> usize {
+        self.store.len()
+    }
 }
 
 impl<K, T: Serialize> IntoIterator for InMemoryKVStore<K, T> {
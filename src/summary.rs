@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use crate::{
+    assets::Assets,
+    metrics::{Metrics, APPLIED},
+    types::{Account, Currency, HoldReason},
+    verify::Ledgers,
+};
+
+// Summary is the report printed at the end of a batch run
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Summary {
+    pub rows_read: u64,
+    pub rows_unparsable: u64,
+    // processed transactions by type and by result (applied or the kind of the error)
+    pub transactions: BTreeMap<&'static str, BTreeMap<&'static str, u64>>,
+    pub accounts: u64,
+    pub locked_accounts: u64,
+    pub frozen_accounts: u64,
+    pub closed_accounts: u64,
+    // accounts with a negative available balance
+    pub deficit_accounts: u64,
+    // the held balances of all accounts per currency, broken down by the reason they are held back for
+    pub held: BTreeMap<Currency, BTreeMap<HoldReason, i128>>,
+    // the credit claimed back by disputes on withdrawals per currency
+    pub pending_credit: BTreeMap<Currency, i128>,
+    pub ledgers: Ledgers,
+    // the precision of the currencies, it's needed to present the volumes
+    pub assets: Assets,
+    pub elapsed: Duration,
+}
+
+impl Summary {
+    pub fn new<I: IntoIterator<Item = Account>>(
+        metrics: &Metrics,
+        ledgers: &Ledgers,
+        assets: &Assets,
+        accounts: I,
+        elapsed: Duration,
+    ) -> Self {
+        let mut summary = Self {
+            rows_read: metrics.rows_read(),
+            rows_unparsable: metrics.rows_unparsable(),
+            ledgers: ledgers.clone(),
+            assets: assets.clone(),
+            elapsed,
+            ..Default::default()
+        };
+        for ((type_, result), count) in metrics.transactions() {
+            summary.transactions.entry(type_).or_default().insert(result, count);
+        }
+        for account in accounts {
+            summary.accounts += 1;
+            if account.locked {
+                summary.locked_accounts += 1;
+            }
+            if account.frozen {
+                summary.frozen_accounts += 1;
+            }
+            if account.closed {
+                summary.closed_accounts += 1;
+            }
+            if account.in_deficit() {
+                summary.deficit_accounts += 1;
+            }
+            let held = summary.held.entry(account.currency).or_default();
+            for reason in HoldReason::ALL {
+                let sum = held.entry(reason).or_default();
+                *sum = sum.saturating_add(account.held_for(reason));
+            }
+            let pending_credit = summary.pending_credit.entry(account.currency).or_default();
+            *pending_credit = pending_credit.saturating_add(account.pending_credit);
+        }
+        summary
+    }
+
+    // count returns the number of transactions with the given result over all types
+    fn count(&self, result: Option<&str>) -> u64 {
+        self.transactions
+            .values()
+            .flat_map(|results| results.iter())
+            .filter(|(r, _)| result.is_none_or(|result| **r == result))
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    // amount formats a volume of the given currency the same way as the output csv formats balances
+    fn amount(&self, currency: Currency, amount: u128) -> String {
+        self.assets
+            .format_amount(currency, i128::try_from(amount).unwrap_or(i128::MAX))
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let processed = self.count(None);
+        let applied = self.count(Some(APPLIED));
+        writeln!(f, "rows read: {}", self.rows_read)?;
+        writeln!(f, "rows unparsable: {}", self.rows_unparsable)?;
+        writeln!(f, "transactions: {} ({} applied, {} rejected)", processed, applied, processed - applied)?;
+        for (type_, results) in &self.transactions {
+            let total: u64 = results.values().sum();
+            let results: Vec<String> = results.iter().map(|(r, c)| format!("{} {}", c, r)).collect();
+            writeln!(f, "  {}: {} ({})", type_, total, results.join(", "))?;
+        }
+
+        let mut rejected: BTreeMap<&str, u64> = BTreeMap::new();
+        for results in self.transactions.values() {
+            for (result, count) in results.iter().filter(|(r, _)| **r != APPLIED) {
+                *rejected.entry(result).or_default() += count;
+            }
+        }
+        writeln!(f, "rejected by reason:")?;
+        for (reason, count) in rejected {
+            writeln!(f, "  {}: {}", reason, count)?;
+        }
+
+        writeln!(
+            f,
+            "accounts: {} ({} locked, {} frozen, {} closed, {} in deficit)",
+            self.accounts, self.locked_accounts, self.frozen_accounts, self.closed_accounts, self.deficit_accounts
+        )?;
+        for (currency, ledger) in &self.ledgers {
+            writeln!(f, "{}:", currency)?;
+            writeln!(f, "  deposited: {}", self.amount(*currency, ledger.deposited))?;
+            writeln!(f, "  withdrawn: {}", self.amount(*currency, ledger.withdrawn))?;
+            writeln!(f, "  charged back: {}", self.amount(*currency, ledger.charged_back))?;
+            writeln!(f, "  reversed withdrawals: {}", self.amount(*currency, ledger.reversed))?;
+            writeln!(f, "  transferred: {}", self.amount(*currency, ledger.transferred))?;
+            writeln!(f, "  fees: {}", self.amount(*currency, ledger.fees))?;
+            if let Some(held) = self.held.get(currency) {
+                let total = held.values().fold(0i128, |sum, amount| sum.saturating_add(*amount));
+                let parts: Vec<String> = held
+                    .iter()
+                    .map(|(reason, amount)| format!("{} {}", self.assets.format_amount(*currency, *amount), reason.as_str()))
+                    .collect();
+                writeln!(f, "  held: {} ({})", self.assets.format_amount(*currency, total), parts.join(", "))?;
+            }
+            if let Some(pending_credit) = self.pending_credit.get(currency) {
+                writeln!(f, "  pending credit: {}", self.assets.format_amount(*currency, *pending_credit))?;
+            }
+        }
+
+        let secs = self.elapsed.as_secs_f64();
+        let throughput = if secs > 0.0 { self.rows_read as f64 / secs } else { 0.0 };
+        writeln!(f, "elapsed: {:.3}s ({:.0} rows/s)", secs, throughput)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TxType;
+    use crate::verify::Ledger;
+
+    #[test]
+    fn test_summary() {
+        let metrics = Metrics::new();
+        for _ in 0..4 {
+            metrics.row_read();
+        }
+        metrics.transaction_processed(&TxType::Deposit, None, Duration::ZERO);
+        metrics.transaction_processed(&TxType::Deposit, None, Duration::ZERO);
+        metrics.transaction_processed(&TxType::Withdrawal, Some("insufficient_funds"), Duration::ZERO);
+        metrics.transaction_processed(&TxType::Dispute, Some("not_found"), Duration::ZERO);
+        let ledgers = Ledgers::from([(
+            Currency::DEFAULT,
+            Ledger {
+                deposited: 25000,
+                ..Default::default()
+            },
+        )]);
+        let mut locked = Account::new(2);
+        locked.locked = true;
+        locked.available = -5;
+        locked.held = 15000;
+        locked.held_dispute = 10000;
+        locked.held_compliance = 5000;
+        locked.total = 14995;
+        locked.pending_credit = 2500;
+
+        let summary = Summary::new(&metrics, &ledgers, &Assets::default(), vec![Account::new(1), locked], Duration::from_secs(2));
+        assert_eq!(summary.accounts, 2);
+        assert_eq!(summary.locked_accounts, 1);
+        assert_eq!(summary.deficit_accounts, 1);
+
+        let report = summary.to_string();
+        assert!(report.contains("transactions: 4 (2 applied, 2 rejected)\n"));
+        assert!(report.contains("  deposit: 2 (2 applied)\n"));
+        assert!(report.contains("  withdrawal: 1 (1 insufficient_funds)\n"));
+        assert!(report.contains("  not_found: 1\n"));
+        assert!(report.contains("accounts: 2 (1 locked, 0 frozen, 0 closed, 1 in deficit)\n"));
+        assert!(report.contains("EUR:\n  deposited: 2.5\n"));
+        assert!(report.contains("  held: 1.5 (1.0 dispute, 0.0 authorization, 0.5 compliance)\n  pending credit: 0.25\n"));
+        assert!(report.contains("elapsed: 2.000s (2 rows/s)\n"));
+    }
+}
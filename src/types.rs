@@ -1,4 +1,7 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::error::{Error, Result};
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub enum TxType {
@@ -12,123 +15,419 @@ pub enum TxType {
     Resolve,
     #[serde(rename = "chargeback")]
     Chargeback,
+    // moves money from the account of the client to the account of the destination client
+    #[serde(rename = "transfer")]
+    Transfer,
+    // two-phase withdrawals: an authorization reserves the amount, a capture withdraws (part of) it and a void releases it
+    #[serde(rename = "authorize")]
+    Authorize,
+    #[serde(rename = "capture")]
+    Capture,
+    #[serde(rename = "void")]
+    Void,
+    // admin operations, these carry an operator id and a reason instead of an amount
+    #[serde(rename = "unlock")]
+    Unlock,
+    #[serde(rename = "freeze")]
+    Freeze,
+    #[serde(rename = "unfreeze")]
+    Unfreeze,
+    #[serde(rename = "close")]
+    Close,
+    // a compliance hold keeps an amount of the account back until it's released again
+    #[serde(rename = "hold")]
+    Hold,
+    #[serde(rename = "release")]
+    Release,
+}
+
+impl TxType {
+    // as_str returns the name of the type as used in the csv files
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Deposit => "deposit",
+            Self::Withdrawal => "withdrawal",
+            Self::Dispute => "dispute",
+            Self::Resolve => "resolve",
+            Self::Chargeback => "chargeback",
+            Self::Transfer => "transfer",
+            Self::Authorize => "authorize",
+            Self::Capture => "capture",
+            Self::Void => "void",
+            Self::Unlock => "unlock",
+            Self::Freeze => "freeze",
+            Self::Unfreeze => "unfreeze",
+            Self::Close => "close",
+            Self::Hold => "hold",
+            Self::Release => "release",
+        }
+    }
+
+    // from_name parses the name of a type as used in the csv files
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "deposit" => Some(Self::Deposit),
+            "withdrawal" => Some(Self::Withdrawal),
+            "dispute" => Some(Self::Dispute),
+            "resolve" => Some(Self::Resolve),
+            "chargeback" => Some(Self::Chargeback),
+            "transfer" => Some(Self::Transfer),
+            "authorize" => Some(Self::Authorize),
+            "capture" => Some(Self::Capture),
+            "void" => Some(Self::Void),
+            "unlock" => Some(Self::Unlock),
+            "freeze" => Some(Self::Freeze),
+            "unfreeze" => Some(Self::Unfreeze),
+            "close" => Some(Self::Close),
+            "hold" => Some(Self::Hold),
+            "release" => Some(Self::Release),
+            _ => None,
+        }
+    }
+
+    // is_admin returns true for the operations that are performed by an operator instead of a client
+    pub fn is_admin(&self) -> bool {
+        matches!(self, Self::Unlock | Self::Freeze | Self::Unfreeze | Self::Close | Self::Hold | Self::Release)
+    }
+
+    // is_disputable returns true for the transactions that move money and can be referenced by disputes,
+    // these are kept in the transaction store
+    pub fn is_disputable(&self) -> bool {
+        matches!(self, Self::Deposit | Self::Withdrawal | Self::Transfer)
+    }
 }
 
 pub type TransactionID = u32;
 
 pub type ClientID = u16;
 
+// Timestamp is the time of a transaction in seconds since the Unix epoch
+pub type Timestamp = u64;
+
+pub const SECONDS_PER_DAY: Timestamp = 24 * 60 * 60;
+
+// Currency is the code of a currency or asset, e.g. `EUR`
+// The code is kept inline instead of as a String, since every stored transaction carries one
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Currency([u8; 8]);
+
+impl Currency {
+    // transactions without a currency are in the currency the platform settles in
+    pub const DEFAULT: Currency = Currency(*b"EUR\0\0\0\0\0");
+
+    // new parses a currency code, codes are up to 8 alphanumeric characters and case insensitive
+    pub fn new(code: &str) -> Result<Self> {
+        if code.is_empty() || code.len() > 8 || !code.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(Error::InvalidCurrency(code.to_string()));
+        }
+        let mut bytes = [0u8; 8];
+        bytes[..code.len()].copy_from_slice(code.to_ascii_uppercase().as_bytes());
+        Ok(Self(bytes))
+    }
+
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|b| *b == 0).unwrap_or(self.0.len());
+        // the bytes are checked to be ascii on creation
+        std::str::from_utf8(&self.0[..len]).unwrap_or_default()
+    }
+}
+
+impl Default for Currency {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl TryFrom<String> for Currency {
+    type Error = Error;
+
+    fn try_from(code: String) -> Result<Self> {
+        Self::new(&code)
+    }
+}
+
+impl From<Currency> for String {
+    fn from(currency: Currency) -> Self {
+        currency.as_str().to_string()
+    }
+}
+
+// Accounts are kept per client and currency
+pub type AccountKey = (ClientID, Currency);
+
 // This is one transaction row as seen in the input csv file
+// The currency column is optional, rows without it are in the default currency
+// The destination column is optional, it's only used by transfers
+// The operator and reason columns are optional, they are only used by admin operations
+// The timestamp column is optional, rows without one happen at the time of the latest row with one
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TransactionRow {
     #[serde(rename = "type")]
     pub type_: TxType,
     pub client: ClientID,
     pub tx: TransactionID,
-    pub amount: Option<f64>,
+    // the amount is parsed with the precision of the currency, see `Assets`
+    pub amount: Option<String>,
+    pub currency: Option<Currency>,
+    pub destination: Option<ClientID>,
+    pub operator: Option<String>,
+    pub reason: Option<String>,
+    pub timestamp: Option<Timestamp>,
+}
+
+impl TransactionRow {
+    // admin returns who performed the operation and why, if the row names an operator
+    pub fn admin(&self) -> Option<Admin> {
+        self.operator.as_ref().map(|operator| Admin {
+            operator: operator.clone(),
+            reason: self.reason.clone().unwrap_or_default(),
+        })
+    }
+}
+
+// Admin records who performed an admin operation and why
+// It's kept apart from `Transaction`, since only the history needs it and all deposits and withdrawals are kept in memory
+#[derive(Debug, Clone, PartialEq)]
+pub struct Admin {
+    pub operator: String,
+    pub reason: String,
+}
+
+// This is one row of the results file, there is one row per processed transaction
+// The result is `applied` or the kind of error the transaction was rejected with, the fees are formatted with the
+// precision of the currency and only written for applied transactions
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ResultRow {
+    #[serde(rename = "type")]
+    pub type_: TxType,
+    pub client: ClientID,
+    pub tx: TransactionID,
+    pub result: String,
+    pub currency: Currency,
+    pub fee: Option<String>,
+    pub refunded_fee: Option<String>,
 }
 
-// This is one account row as seen in the output csv file
+// This is one account row as seen in the output csv file, there is one row per client and currency
+// The balances are formatted with the precision of the currency, see `Assets`
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AccountRow {
     pub id: ClientID,
-    pub available: f64,
-    pub held: f64,
-    pub total: f64,
+    // the column is only written if there are accounts in other currencies than the default one, see `Assets::account_rows`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub available: String,
+    pub held: String,
+    pub total: String,
     pub locked: bool,
+    // the column is only written if it's asked for, see `--pending-credit`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_credit: Option<String>,
+    // the column is only written if it's asked for, see `--deficit`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deficit: Option<bool>,
 }
 
 // This is the internal representation of transactions
-// The actual amount is saved as a u64 to prevent precision loss when calculating
-// the amount here is the the actual amount as seen in the csv * 10000
+// The actual amount is saved as an integer of the smallest unit of the currency to prevent precision loss when calculating,
+// e.g. cents for a currency with two decimal places. u128 leaves enough room for assets with up to 18 decimal places.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub type_: TxType,
     pub client: ClientID,
     pub tx: TransactionID,
-    pub amount: Option<u64>,
+    pub amount: Option<u128>,
+    #[serde(default)]
+    pub currency: Currency,
+    // the client that receives the money of a transfer
+    #[serde(default)]
+    pub destination: Option<ClientID>,
+    // the fee charged when the transaction was applied, it's kept so a chargeback can refund it
+    #[serde(default)]
+    pub fee: u128,
+    // the part charged back so far when no hold keeps track of it, so the fee of a part is only refunded once
+    #[serde(default)]
+    pub charged_back: u128,
+    // when the transaction happened, if the input says so, it's kept for the dispute window
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+}
+
+// ChargedBack is the part of a transaction that was charged back so far without a hold keeping track of it,
+// it's persisted with the snapshots of the history, so a replay doesn't refund its fee again
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ChargedBack {
+    pub tx: TransactionID,
+    pub amount: u128,
+}
+
+// HoldReason is why an amount of an account is held back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub enum HoldReason {
+    #[serde(rename = "dispute")]
+    Dispute,
+    #[serde(rename = "authorization")]
+    Authorization,
+    #[serde(rename = "compliance")]
+    Compliance,
+}
+
+impl HoldReason {
+    pub const ALL: [HoldReason; 3] = [Self::Dispute, Self::Authorization, Self::Compliance];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Dispute => "dispute",
+            Self::Authorization => "authorization",
+            Self::Compliance => "compliance",
+        }
+    }
 }
 
 // This is the internal representation of accounts
-// The actual amounts are saved as a u64 to prevent precision loss when calculating
-// the amount here is the the actual amount as seen in the csv * 10000
-#[derive(Debug, Clone, Deserialize, Serialize)]
+// The amounts are integers of the smallest unit of the currency, like the amounts of transactions
+// The balances are signed, since depending on the policy a dispute can hold back more than is available
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Account {
     pub id: ClientID,
-    pub available: u64,
-    pub held: u64,
-    pub total: u64,
+    #[serde(default)]
+    pub currency: Currency,
+    pub available: i128,
+    // the sum of everything that is held back, see the break down by reason below
+    pub held: i128,
+    pub total: i128,
     pub locked: bool,
+    // frozen accounts can still receive money, but nothing can be withdrawn
+    #[serde(default)]
+    pub frozen: bool,
+    // closed accounts reject every transaction
+    #[serde(default)]
+    pub closed: bool,
+    // the held balance broken down by the reason it's held back for
+    #[serde(default)]
+    pub held_dispute: i128,
+    #[serde(default)]
+    pub held_authorization: i128,
+    #[serde(default)]
+    pub held_compliance: i128,
+    // the disputed withdrawals the client claims back, they are credited if they are charged back
+    // it's not part of the total, the money is not in the account (yet)
+    #[serde(default)]
+    pub pending_credit: i128,
 }
 
 impl Account {
     pub fn new(id: ClientID) -> Account {
         Account {
             id,
+            currency: Currency::DEFAULT,
             available: 0,
             held: 0,
             total: 0,
             locked: false,
+            frozen: false,
+            closed: false,
+            held_dispute: 0,
+            held_authorization: 0,
+            held_compliance: 0,
+            pending_credit: 0,
         }
     }
-}
 
-impl Default for Account {
-    fn default() -> Self {
-        Self {
-            id: 0,
-            available: 0,
-            held: 0,
-            total: 0,
-            locked: false,
-        }
+    pub fn key(&self) -> AccountKey {
+        (self.id, self.currency)
     }
-}
 
-impl From<AccountRow> for Account {
-    fn from(row: AccountRow) -> Self {
-        Self {
-            id: row.id,
-            available: (row.available * 10000f64) as u64,
-            held: (row.held * 10000f64) as u64,
-            total: (row.total * 10000f64) as u64,
-            locked: row.locked,
+    // held_for returns the part of the held balance that is held back for the given reason
+    pub fn held_for(&self, reason: HoldReason) -> i128 {
+        match reason {
+            HoldReason::Dispute => self.held_dispute,
+            HoldReason::Authorization => self.held_authorization,
+            HoldReason::Compliance => self.held_compliance,
         }
     }
-}
 
-impl From<TransactionRow> for Transaction {
-    fn from(row: TransactionRow) -> Self {
-        Self {
-            type_: row.type_,
-            client: row.client,
-            tx: row.tx,
-            amount: row.amount.map(|x| (x * 10000f64) as u64),
+    fn held_for_mut(&mut self, reason: HoldReason) -> &mut i128 {
+        match reason {
+            HoldReason::Dispute => &mut self.held_dispute,
+            HoldReason::Authorization => &mut self.held_authorization,
+            HoldReason::Compliance => &mut self.held_compliance,
         }
     }
-}
 
-impl From<Transaction> for TransactionRow {
-    fn from(tx: Transaction) -> Self {
-        Self {
-            type_: tx.type_,
-            client: tx.client,
-            tx: tx.tx,
-            amount: tx.amount.map(|x| (x as f64) / 10000f64),
-        }
+    // hold moves the amount from the available into the held balance for the given reason
+    pub fn hold(&mut self, reason: HoldReason, amount: i128) -> Result<()> {
+        let held = self.held_for(reason).checked_add(amount).ok_or(Error::Overflow)?;
+        self.held = self.held.checked_add(amount).ok_or(Error::Overflow)?;
+        self.available = self.available.checked_sub(amount).ok_or(Error::Overflow)?;
+        *self.held_for_mut(reason) = held;
+        Ok(())
+    }
+
+    // release moves an amount that was held back for the given reason into the available balance again
+    pub fn release(&mut self, reason: HoldReason, amount: i128) {
+        *self.held_for_mut(reason) -= amount;
+        self.held -= amount;
+        self.available += amount;
+    }
+
+    // take removes an amount that was held back for the given reason from the account, e.g. when it's charged back
+    pub fn take(&mut self, reason: HoldReason, amount: i128) {
+        *self.held_for_mut(reason) -= amount;
+        self.held -= amount;
+        self.total -= amount;
+    }
+
+    // in_deficit returns true if the account owes money, i.e. more was held back or charged back than was available
+    pub fn in_deficit(&self) -> bool {
+        self.available < 0
     }
 }
 
-impl From<Account> for AccountRow {
-    fn from(account: Account) -> Self {
-        Self {
-            id: account.id,
-            available: account.available as f64 / 10000f64,
-            held: account.held as f64 / 10000f64,
-            total: account.total as f64 / 10000f64,
-            locked: account.locked,
-        }
+// Hold is an amount held back on an account for the transaction with the given id: the disputed transaction,
+// the authorization or the compliance hold. It stays held back until it's resolved, charged back, captured,
+// voided or released, or until it expires
+// A transaction can be disputed in parts, its dispute hold then keeps track of how much of it is still disputed
+// and how much was charged back already. The hold of a transaction that was (partly) charged back is kept even
+// when nothing is held back anymore, so the charged back part can't be disputed again.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Hold {
+    pub tx: TransactionID,
+    pub reason: HoldReason,
+    pub client: ClientID,
+    pub currency: Currency,
+    // the amount held back, for disputes it's less than the disputed amount if the account couldn't cover it
+    pub amount: u128,
+    // the sequence number of the last transaction before the hold is released again, if it expires at all
+    pub expires: Option<u64>,
+    // the amount of the transaction that is disputed and not resolved or charged back yet
+    #[serde(default)]
+    pub disputed: u128,
+    #[serde(default)]
+    pub charged_back: u128,
+    // the time after which a dispute that is still open is resolved, if the policy sets a deadline
+    #[serde(default)]
+    pub deadline: Option<Timestamp>,
+}
+
+impl Hold {
+    // settled returns true if the hold is of a dispute that was resolved without anything charged back
+    pub fn settled(&self) -> bool {
+        self.reason == HoldReason::Dispute && self.amount == 0 && self.disputed == 0 && self.charged_back == 0
     }
 }
 
@@ -139,6 +438,11 @@ impl Default for Transaction {
             client: 0,
             tx: 0,
             amount: None,
+            currency: Currency::DEFAULT,
+            destination: None,
+            fee: 0,
+            charged_back: 0,
+            timestamp: None,
         }
     }
 }
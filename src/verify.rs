@@ -0,0 +1,177 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::{
+    error::{Error, Result},
+    types::{Account, Currency, HoldReason},
+};
+
+// VerifyMode controls when the account invariants are checked
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum VerifyMode {
+    #[default]
+    Off,
+    // check the touched account and the global conservation after every transaction
+    EachTransaction,
+    // check all accounts and the global conservation once all transactions are processed
+    End,
+}
+
+// Ledger keeps track of the volumes that actually moved in or out of the system
+// All amounts are in the internal representation of the currency of the ledger
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Ledger {
+    // sum of all applied deposits
+    pub deposited: u128,
+    // sum of all applied withdrawals
+    pub withdrawn: u128,
+    // sum of all amounts taken out of accounts by chargebacks on deposits
+    pub charged_back: u128,
+    // sum of all amounts credited to accounts by chargebacks on withdrawals
+    pub reversed: u128,
+    // sum of all applied transfers between accounts
+    // they move money within the system, so they don't change its balance
+    pub transferred: u128,
+    // sum of all fees posted to the house account less the refunded ones, they move money within the system as well
+    pub fees: u128,
+}
+
+// Every currency has its own ledger, amounts of different currencies can't be added up
+pub type Ledgers = BTreeMap<Currency, Ledger>;
+
+impl Ledger {
+    // balance returns the amount of money that should be in the system according to the ledger
+    pub fn balance(&self) -> i128 {
+        self.deposited as i128 - self.withdrawn as i128 - self.charged_back as i128
+            + self.reversed as i128
+    }
+}
+
+// check_account verifies that the balances of the account add up
+pub fn check_account(account: &Account) -> Result<()> {
+    if account.held < 0 {
+        return Err(Error::InvariantViolation(format!(
+            "client {}: held {} is negative",
+            account.id, account.held
+        )));
+    }
+    let mut held = 0i128;
+    for reason in HoldReason::ALL {
+        let part = account.held_for(reason);
+        if part < 0 {
+            return Err(Error::InvariantViolation(format!(
+                "client {}: held for {} {} is negative",
+                account.id,
+                reason.as_str(),
+                part
+            )));
+        }
+        held = held.saturating_add(part);
+    }
+    if account.pending_credit < 0 {
+        return Err(Error::InvariantViolation(format!(
+            "client {}: pending credit {} is negative",
+            account.id, account.pending_credit
+        )));
+    }
+    if held != account.held {
+        return Err(Error::InvariantViolation(format!(
+            "client {}: held {} != sum of the holds by reason {}",
+            account.id, account.held, held
+        )));
+    }
+    match account.available.checked_add(account.held) {
+        Some(sum) if sum == account.total => Ok(()),
+        _ => Err(Error::InvariantViolation(format!(
+            "client {}: available {} + held {} != total {}",
+            account.id, account.available, account.held, account.total
+        ))),
+    }
+}
+
+// check_conservation verifies that the sum of all account totals in the currency matches its ledger
+pub fn check_conservation(currency: Currency, ledger: &Ledger, sum_of_totals: i128) -> Result<()> {
+    if ledger.balance() != sum_of_totals {
+        return Err(Error::InvariantViolation(format!(
+            "{}: deposited {} - withdrawn {} - charged back {} + reversed {} != sum of totals {}",
+            currency, ledger.deposited, ledger.withdrawn, ledger.charged_back, ledger.reversed, sum_of_totals
+        )));
+    }
+    Ok(())
+}
+
+// check_accounts verifies every account and the global conservation over all accounts of every currency
+pub fn check_accounts<I: IntoIterator<Item = Account>>(accounts: I, ledgers: &Ledgers) -> Result<()> {
+    let mut sums_of_totals: BTreeMap<Currency, i128> = ledgers.keys().map(|c| (*c, 0)).collect();
+    for account in accounts {
+        check_account(&account)?;
+        let sum = sums_of_totals.entry(account.currency).or_default();
+        *sum = sum.checked_add(account.total).ok_or_else(|| {
+            Error::InvariantViolation(format!("{}: sum of totals overflows", account.currency))
+        })?;
+    }
+    let no_ledger = Ledger::default();
+    for (currency, sum_of_totals) in sums_of_totals {
+        check_conservation(currency, ledgers.get(&currency).unwrap_or(&no_ledger), sum_of_totals)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_accounts() {
+        let ledger = Ledger {
+            deposited: 300,
+            withdrawn: 50,
+            charged_back: 100,
+            reversed: 20,
+            ..Default::default()
+        };
+        let ledger = Ledgers::from([(Currency::DEFAULT, ledger)]);
+        let mut a = Account::new(1);
+        a.available = 100;
+        a.held = 50;
+        a.held_dispute = 50;
+        a.total = 150;
+        let mut b = Account::new(2);
+        b.available = 20;
+        b.total = 20;
+        assert!(check_accounts(vec![a.clone(), b.clone()], &ledger).is_ok());
+
+        // missing money
+        b.available = 10;
+        b.total = 10;
+        assert!(check_accounts(vec![a.clone(), b.clone()], &ledger).is_err());
+
+        // money of another currency doesn't make up for it
+        let mut usd = Account::new(2);
+        usd.currency = Currency::new("USD").unwrap();
+        usd.available = 10;
+        usd.total = 10;
+        assert!(check_accounts(vec![a.clone(), b.clone(), usd], &ledger).is_err());
+
+        // balances of a single account don't add up
+        a.held = 40;
+        assert!(check_account(&a).is_err());
+        // neither do the holds by reason
+        a.held = 50;
+        a.held_dispute = 20;
+        a.held_compliance = 20;
+        assert!(check_account(&a).is_err());
+
+        // the available balance may be negative, the held balance may not
+        let mut c = Account::new(3);
+        c.available = -50;
+        c.held = 100;
+        c.held_dispute = 100;
+        c.total = 50;
+        assert!(check_account(&c).is_ok());
+        c.available = 200;
+        c.held = -150;
+        c.held_dispute = -150;
+        assert!(check_account(&c).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+// Golden file tests: the engine is run on every fixture in `tests/` and its output is compared
+// with the checked-in expectation in `tests/expected/`.
+// The output order of the accounts is not defined, so the rows are sorted by client id and currency before comparing.
+//
+// When the behavior of the engine changes on purpose, the expectations can be regenerated with
+//   UPDATE_GOLDEN=1 cargo test --test golden
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const FIXTURES: &[&str] = &[
+    "example_from_task.csv",
+    "100.csv",
+    "10_000.csv",
+    "100_000.csv",
+    "many_clients.csv",
+    // generated with `tx-engine generate --seed 1 --clients 50 --rows 2000`, the expectation is the one written by the generator
+    "generated.csv",
+];
+
+fn tests_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests")
+}
+
+// run executes the engine on the given input and returns its output with the account rows sorted by client id and currency
+fn run(input: &Path) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_tx-engine"))
+        .arg(input)
+        .output()
+        .expect("failed to run tx-engine");
+    assert!(output.status.success(), "tx-engine failed on {}", input.display());
+
+    let stdout = String::from_utf8(output.stdout).expect("output is not utf-8");
+    let mut lines = stdout.lines();
+    let header = lines.next().unwrap_or_default();
+    let mut rows: Vec<&str> = lines.collect();
+    rows.sort_by_key(|row| {
+        let mut columns = row.split(',');
+        let id = columns.next().and_then(|id| id.parse::<u32>().ok()).unwrap_or(u32::MAX);
+        (id, columns.next().unwrap_or_default().to_string())
+    });
+
+    let mut sorted = String::from(header);
+    sorted.push('\n');
+    for row in rows {
+        sorted.push_str(row);
+        sorted.push('\n');
+    }
+    sorted
+}
+
+#[test]
+fn test_golden_files() {
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    let mut failed = Vec::new();
+
+    for fixture in FIXTURES {
+        let input = tests_dir().join(fixture);
+        let expected_path = tests_dir().join("expected").join(fixture);
+        let actual = run(&input);
+
+        if update {
+            fs::create_dir_all(expected_path.parent().unwrap()).unwrap();
+            fs::write(&expected_path, &actual).unwrap();
+            continue;
+        }
+
+        let expected = fs::read_to_string(&expected_path)
+            .unwrap_or_else(|e| panic!("can't read {}: {}", expected_path.display(), e));
+        if actual != expected {
+            let first_diff = actual
+                .lines()
+                .zip(expected.lines())
+                .find(|(a, e)| a != e)
+                .map(|(a, e)| format!("got `{}`, expected `{}`", a, e))
+                .unwrap_or_else(|| "different number of rows".to_string());
+            failed.push(format!("{}: {}", fixture, first_diff));
+        }
+    }
+
+    assert!(
+        failed.is_empty(),
+        "output differs from the golden files (rerun with UPDATE_GOLDEN=1 if intended):\n{}",
+        failed.join("\n")
+    );
+}
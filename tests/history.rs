@@ -0,0 +1,164 @@
+// History round trip tests: a run is recorded with `--history`, loaded again, and the state of every account that
+// `accounts_at` replays is compared with the state of a run that stops right at that point of the input.
+// The input covers what a replay has to restore: rejected events, open holds and disputes past their deadline,
+// withdrawals that count against the limits, charged back parts, fees and negative balances.
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tx_engine::{
+    cli::ProcessOptions,
+    error::{Error, Result},
+    history,
+    hooks::{Hooks, Verdict},
+    metrics::Metrics,
+    pipeline::{self, Outcome},
+    types::{Account, ClientID, Transaction},
+    verify::VerifyMode,
+};
+
+const T: u64 = 1_700_000_000;
+const DAY: u64 = 24 * 3600;
+const CLIENTS: &[ClientID] = &[1, 2, 3, 4, 5, 9];
+
+// rows returns the rows of the recorded run
+fn rows() -> Vec<String> {
+    vec![
+        format!("deposit,1,1,100.0,,,,,{}", T),
+        format!("deposit,2,2,500.0,,,,,{}", T + 60),
+        // client 5 is rejected by the validator of the run
+        format!("deposit,5,3,10.0,,,,,{}", T + 120),
+        format!("withdrawal,1,4,60.0,,,,,{}", T + 180),
+        // the full hold leaves client 1 with a negative available balance
+        format!("dispute,1,1,,,,,,{}", T + 240),
+        format!("deposit,3,5,1000,JPY,,,,{}", T + 300),
+        format!("transfer,2,6,20.0,,3,,,{}", T + 360),
+        format!("withdrawal,2,7,80.0,,,,,{}", T + 420),
+        format!("withdrawal,1,8,10.0,,,,,{}", T + 480),
+        // breaks the daily limit
+        format!("withdrawal,2,9,30.0,,,,,{}", T + 540),
+        format!("chargeback,2,7,30.0,,,,,{}", T + 600),
+        format!("chargeback,2,7,20.0,,,,,{}", T + 660),
+        format!("authorize,2,11,40.0,,,,,{}", T + 720),
+        format!("capture,2,11,25.0,,,,,{}", T + 780),
+        // expires before it's captured
+        format!("authorize,3,12,5.0,,,,,{}", T + 840),
+        format!("chargeback,1,1,,,,,,{}", T + 900),
+        // after the snapshot that recorded the parts charged back before
+        format!("chargeback,2,7,30.0,,,,,{}", T + 930),
+        format!("freeze,3,13,,,,ops,review,{}", T + 960),
+        format!("withdrawal,3,14,1.0,,,,,{}", T + 1020),
+        format!("capture,3,12,,,,,,{}", T + 1080),
+        format!("dispute,2,2,100.0,,,,,{}", T + 2 * DAY),
+        format!("unfreeze,3,15,,,,ops,cleared,{}", T + 2 * DAY + 60),
+        format!("deposit,1,16,10.0,,,,,{}", T + 2 * DAY + 120),
+        // past the deadline of the dispute of tx 2
+        format!("deposit,4,17,1.0,,,,,{}", T + 4 * DAY),
+        format!("withdrawal,2,18,50.0,,,,,{}", T + 4 * DAY + 60),
+        format!("chargeback,2,2,,,,,,{}", T + 4 * DAY + 120),
+    ]
+}
+
+// hooks returns the validator of the recorded run, which rejects everything of client 5
+fn hooks() -> Hooks {
+    Hooks::new().with_validator(|tx: &Transaction, _: &Account| match tx.client {
+        5 => Verdict::Reject("blocked".to_string()),
+        _ => Verdict::Accept,
+    })
+}
+
+// run processes the first `count` rows, recording the history into the given directory if any
+async fn run(opts: &ProcessOptions, count: usize, history: Option<PathBuf>) -> Result<Outcome> {
+    let mut input = String::from("type,client,tx,amount,currency,destination,operator,reason,timestamp\n");
+    for row in rows().iter().take(count) {
+        input.push_str(row);
+        input.push('\n');
+    }
+    let opts = ProcessOptions { history, ..opts.clone() };
+    pipeline::run_with_hooks(Cursor::new(input.into_bytes()), &opts, Arc::new(Metrics::new()), hooks()).await
+}
+
+// options writes the configuration of the run into the directory and returns the options that use it
+fn options(dir: &Path, policy: &str) -> Result<ProcessOptions> {
+    let conf = |name: &str, content: &str| -> Result<PathBuf> {
+        let path = dir.join(name);
+        std::fs::write(&path, content)?;
+        Ok(path)
+    };
+    Ok(ProcessOptions {
+        input: PathBuf::from("-"),
+        history: None,
+        snapshot_interval: 4,
+        verify: VerifyMode::EachTransaction,
+        policy: Some(conf("policy.conf", policy)?),
+        assets: Some(conf("assets.conf", "JPY = 0\n")?),
+        fees: Some(conf("fees.conf", "house = 9\nrefund_on_chargeback = true\ndeposit = 1%\nwithdrawal = 0.5\n")?),
+        limits: Some(conf("limits.conf", "default.daily = 100\n")?),
+        metrics: None,
+        metrics_addr: None,
+        results: None,
+        summary: None,
+        pending_credit: false,
+        deficit: false,
+    })
+}
+
+// accounts returns the accounts of the client in the outcome, sorted by currency like `accounts_at` returns them
+fn accounts(outcome: &Outcome, client: ClientID) -> Vec<Account> {
+    let mut accounts: Vec<Account> =
+        outcome.accounts.clone().into_iter().map(|(_, a)| a).filter(|a| a.id == client).collect();
+    accounts.sort_by_key(|a| a.currency);
+    accounts
+}
+
+async fn round_trip(name: &str, policy: &str) -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("tx-engine-history-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let opts = options(&dir, policy)?;
+    let count = rows().len();
+    let recorded = run(&opts, count, Some(dir.join("history"))).await?;
+    let history = history::load(&dir.join("history")).await?;
+
+    // the run had everything the replay has to restore
+    let events = std::fs::read_to_string(dir.join("history").join("events.csv"))?;
+    assert!(events.contains(",rejected") && events.contains(",limit_exceeded"));
+    assert!(dir.join("history").join("chargebacks-12.csv").exists());
+    assert!(accounts(&recorded, 1)[0].total < 0);
+
+    for seq in 1..=count {
+        let live = run(&opts, seq, None).await?;
+        for &client in CLIENTS {
+            let replayed = match history.accounts_at(client, seq as u64).await {
+                Ok(accounts) => accounts,
+                Err(Error::NotFound) => Vec::new(),
+                Err(err) => return Err(err),
+            };
+            assert_eq!(
+                format!("{:?}", replayed),
+                format!("{:?}", accounts(&live, client)),
+                "{}: client {} at {}",
+                name,
+                client,
+                seq
+            );
+        }
+    }
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_history_round_trip_tracked_disputes() -> Result<()> {
+    round_trip(
+        "tracked",
+        "dispute_hold = full\ndispute_tracking = transaction\ndispute_amounts = partial\nlock_on = never\n\
+         authorization_expiry = 4\ndispute_deadline = 1\n",
+    )
+    .await
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_history_round_trip_account_disputes() -> Result<()> {
+    round_trip("account", "dispute_hold = full\nlock_on = never\nauthorization_expiry = 4\n").await
+}